@@ -1,4 +1,12 @@
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
 use clap::{Parser, ValueEnum};
+use ntp_client::ExtensionField;
+
+use crate::system_config::{parse_server, ConfiguredServer};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -13,9 +21,201 @@ pub struct Cli {
     // Time standard to use for output
     #[arg(short, long = "use-standard", default_value = "rfc3339")]
     std: TimeStandard,
-    // Datetime value, used when the action is "set"
+    // Datetime value, used when the action is "set", "epoch" or "format"; the first file for
+    // "compare-runs"
     #[arg()]
     datetime: Option<String>,
+    // Second file, used when the action is "compare-runs"
+    #[arg()]
+    other: Option<PathBuf>,
+    // Date (YYYY-MM-DD) to set while keeping the current time of day
+    #[arg(long, conflicts_with = "datetime")]
+    date: Option<String>,
+    // Time of day (HH:MM:SS) to set while keeping the current date
+    #[arg(long, conflicts_with = "datetime")]
+    time: Option<String>,
+    // Iteratively discard outlying offsets before averaging
+    #[arg(long)]
+    robust: bool,
+    // Discard offsets more than K median absolute deviations from the median before averaging,
+    // in a single pass; with --robust, which removes them one at a time, this sets its K
+    // (3 by default)
+    #[arg(long, value_name = "K", value_parser = parse_max_deviation)]
+    max_deviation: Option<f64>,
+    // Report offsets relative to this server instead of the local clock
+    #[arg(long)]
+    reference: Option<String>,
+    // Warn and exit with an error when an unsynchronized server was excluded
+    #[arg(long)]
+    warn_if_unsynced: bool,
+    // Bind the local socket to a random port within this range, e.g. 49152-49200
+    #[arg(long, value_parser = parse_port_range)]
+    source_port_range: Option<RangeInclusive<u16>>,
+    // Query the servers configured in /etc/ntp.conf or chrony.conf
+    #[arg(long)]
+    from_system_config: bool,
+    // Server to query instead of the defaults, optionally with a version, e.g. "ntp.corp version=4";
+    // may be repeated
+    #[arg(long = "server", value_name = "HOST", value_parser = parse_server, conflicts_with = "from_system_config")]
+    servers: Vec<ConfiguredServer>,
+    // UDP port the servers are queried at
+    #[arg(long, default_value_t = 123, value_parser = clap::value_parser!(u16).range(1..))]
+    ntp_port: u16,
+    // NTP version to send to servers that do not set their own with "version=N"
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(3..=4))]
+    ntp_version: u8,
+    // Number of times to poll the servers
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    count: u32,
+    // Seconds to wait between polls
+    #[arg(long, default_value_t = 10)]
+    interval: u64,
+    // Warn and exit with an error when the offset changes by more than this between polls
+    #[arg(long, value_name = "MS")]
+    max_delta_per_poll: Option<u64>,
+    // Reject responses that do not come from the queried port. Also reports queries to closed
+    // ports as refused, which otherwise show as timeouts
+    #[arg(long)]
+    strict_source_port: bool,
+    // Largest offset still considered GOOD accuracy
+    #[arg(long, default_value_t = 50)]
+    good_ms: u64,
+    // Largest offset still considered FAIR accuracy
+    #[arg(long, default_value_t = 500)]
+    fair_ms: u64,
+    // Diagnostics only: append zero bytes to the request; strict servers may drop it
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u16).range(..=65_459)
+    )]
+    pad: u16,
+    // File that keeps the previous offset to report drift between runs
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    // Apply the suggested frequency correction with adjtimex (Linux only)
+    #[arg(long, requires = "state_file")]
+    apply_freq: bool,
+    // Run this many rounds and use the one with the smallest combined delay
+    #[arg(
+        long,
+        value_name = "K",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    best_of: u32,
+    // Write the check-ntp result as JSON to this file
+    #[arg(long)]
+    output: Option<PathBuf>,
+    // Known path asymmetry: outbound minus return one-way delay (positive for a slow uplink)
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 0,
+        allow_hyphen_values = true
+    )]
+    asymmetry: i64,
+    // Read the epoch value as an NTP timestamp (seconds.fraction)
+    #[arg(long, conflicts_with_all = ["from_unix", "to_ntp"])]
+    from_ntp: bool,
+    // Read the epoch value as a Unix timestamp
+    #[arg(long)]
+    from_unix: bool,
+    // Print the epoch value as an NTP timestamp (seconds.fraction)
+    #[arg(long)]
+    to_ntp: bool,
+    // Output format for check-ntp: human-readable text, just the offset in milliseconds (raw),
+    // a time x server offset matrix, or one JSON object per poll
+    #[arg(long, default_value = "human")]
+    format: OutputFormat,
+    // Number of requests sent to each server; the lowest-delay response is used
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    samples: u32,
+    // Drop servers for which fewer than this many samples succeeded
+    #[arg(long, value_name = "K", default_value_t = 1)]
+    min_valid_samples: u32,
+    // After setting or syncing the time, also write it to the hardware clock (Linux)
+    #[arg(long)]
+    sync_rtc: bool,
+    // The hardware clock keeps local time instead of UTC
+    #[arg(long, requires = "sync_rtc")]
+    rtc_local: bool,
+    // Save each server's raw response in this directory, one file per server
+    #[arg(long, value_name = "DIR")]
+    save_response: Option<PathBuf>,
+    // Time standard of the value given to "format"
+    #[arg(long)]
+    from: Option<TimeStandard>,
+    // Time standard that "format" converts the value to
+    #[arg(long)]
+    to: Option<TimeStandard>,
+    // Refuse to report a time when the server offsets span more than this
+    #[arg(long, value_name = "MS")]
+    max_spread: Option<u64>,
+    // Experimental: query over TLS on port 4460 (needs the "tls" feature; lower precision)
+    #[arg(long)]
+    tls: bool,
+    // Print each server's t1-t4 as raw NTP timestamps (seconds.fraction) next to the datetimes
+    #[arg(long)]
+    show_timestamps: bool,
+    // Cap displayed offsets at this magnitude; measurements and state keep the real value
+    #[arg(long, value_name = "MS")]
+    clamp_offset: Option<u64>,
+    // Query each server's IPv4 and IPv6 addresses separately and compare them
+    #[arg(long, conflicts_with = "reference")]
+    compare_families: bool,
+    // Give up on a server whose name does not resolve within this many milliseconds
+    #[arg(long, value_name = "MS")]
+    resolve_timeout: Option<u64>,
+    // Give up on a server that does not answer within this many milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout_ms: u64,
+    // Refuse to step the clock by more than this when syncing
+    #[arg(long, value_name = "MS")]
+    max_step: Option<u64>,
+    // Measure and report, but do not step the clock
+    #[arg(long)]
+    dry_run: bool,
+    // Step the clock even by more than a few hours when syncing
+    #[arg(long)]
+    force: bool,
+    // Query only one server per subnet: an IPv4 prefix length, optionally followed by an IPv6
+    // one (default 48), e.g. 24 or 24,56. A name with several addresses is queried at one in a
+    // subnet not yet taken
+    #[arg(long, value_name = "PREFIX", value_parser = parse_subnet_prefixes)]
+    dedup_by_subnet: Option<(u8, u8)>,
+    // How to weight offsets: delay (1e6/delay²) or combined (1e6/delay² × 2^-(stratum-1))
+    #[arg(long, default_value = "delay")]
+    weighting: WeightingArg,
+    // Exit with an error when any server failed, even if the others gave an offset
+    #[arg(long)]
+    fail_on_any: bool,
+    // Experimental: append an NTPv4 extension field, given as hex TYPE:VALUE (e.g. 0104:cafe),
+    // and report whether servers echo it; servers may drop such requests
+    #[arg(
+        long,
+        value_name = "TYPE:VALUE",
+        value_parser = parse_extension_field,
+        conflicts_with = "pad"
+    )]
+    extension_field: Option<ExtensionField>,
+    // Wait this long between measuring and stepping when syncing
+    #[arg(long, value_name = "MS")]
+    settle: Option<u64>,
+    // Measure the offset again after --settle and step by the new one
+    #[arg(long, requires = "settle")]
+    remeasure: bool,
+    // Encoding of the --format matrix output
+    #[arg(long, default_value = "csv")]
+    matrix_format: MatrixFormat,
+    // Accept servers that leave the reference id/timestamp (and receive timestamp) unset,
+    // as low-confidence SNTP estimates
+    #[arg(long)]
+    sntp_fallback: bool,
+    // Print each response's header fields, timestamps, delay and offset to stderr
+    #[arg(short, long)]
+    verbose: bool,
 }
 
 #[derive(Debug, ValueEnum, Clone)]
@@ -23,6 +223,34 @@ pub enum Action {
     Get,
     Set,
     CheckNtp,
+    #[value(alias = "sync-ntp")]
+    Sync,
+    CompareRuns,
+    Epoch,
+    Format,
+    ExplainPacket,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum OutputFormat {
+    Human,
+    Raw,
+    // A time x server matrix of offsets, written at the end of the run
+    Matrix,
+    // One JSON object per poll with the offset and every server's result
+    Json,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum MatrixFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum WeightingArg {
+    Delay,
+    Combined,
 }
 
 #[derive(Debug, ValueEnum, Clone)]
@@ -32,6 +260,71 @@ pub enum TimeStandard {
     Timestamp,
 }
 
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (lo, hi) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected <lo-hi>, got {}", s))?;
+    let lo: u16 = lo
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid port {}", lo))?;
+    let hi: u16 = hi
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid port {}", hi))?;
+    if lo == 0 || lo > hi {
+        return Err(format!("invalid port range {}-{}", lo, hi));
+    }
+    Ok(lo..=hi)
+}
+
+fn parse_extension_field(s: &str) -> Result<ExtensionField, String> {
+    let (field_type, value) = s.split_once(':').unwrap_or((s, ""));
+    let field_type = u16::from_str_radix(field_type.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("invalid field type {}", field_type))?;
+    if !value.is_ascii() || !value.len().is_multiple_of(2) {
+        return Err(format!("invalid hex value {}", value));
+    }
+    let value = (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("invalid hex value {}", value))?;
+    if value.len() > 1024 {
+        return Err("value must be at most 1024 bytes".to_string());
+    }
+    Ok(ExtensionField { field_type, value })
+}
+
+fn parse_subnet_prefixes(s: &str) -> Result<(u8, u8), String> {
+    let (v4, v6) = s.split_once(',').unwrap_or((s, "48"));
+    let v4: u8 = v4
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid prefix length {}", v4))?;
+    let v6: u8 = v6
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid prefix length {}", v6))?;
+    if v4 > 32 || v6 > 128 {
+        return Err(format!(
+            "prefix lengths must be at most 32 (IPv4) and 128 (IPv6), got {},{}",
+            v4, v6
+        ));
+    }
+    Ok((v4, v6))
+}
+
+fn parse_max_deviation(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(k) if k.is_finite() && k > 0.0 => Ok(k),
+        _ => Err(format!(
+            "invalid deviation {} (expected a positive number)",
+            s
+        )),
+    }
+}
+
 impl Cli {
     pub fn get_action(&self) -> &Action {
         &self.action
@@ -43,8 +336,224 @@ impl Cli {
 
     pub fn get_datetime(&self) -> Option<&str> {
         match &self.datetime {
-            Some(datetime) => Some(&datetime),
+            Some(datetime) => Some(datetime),
             None => None,
         }
     }
+
+    pub fn get_date(&self) -> Option<&str> {
+        self.date.as_deref()
+    }
+
+    pub fn get_time(&self) -> Option<&str> {
+        self.time.as_deref()
+    }
+
+    pub fn get_robust(&self) -> bool {
+        self.robust
+    }
+
+    pub fn get_reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    pub fn get_warn_if_unsynced(&self) -> bool {
+        self.warn_if_unsynced
+    }
+
+    pub fn get_source_port_range(&self) -> Option<&RangeInclusive<u16>> {
+        self.source_port_range.as_ref()
+    }
+
+    pub fn get_from_system_config(&self) -> bool {
+        self.from_system_config
+    }
+
+    pub fn get_count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn get_interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn get_max_delta_per_poll(&self) -> Option<u64> {
+        self.max_delta_per_poll
+    }
+
+    pub fn get_strict_source_port(&self) -> bool {
+        self.strict_source_port
+    }
+
+    pub fn get_good_ms(&self) -> u64 {
+        self.good_ms
+    }
+
+    pub fn get_fair_ms(&self) -> u64 {
+        self.fair_ms
+    }
+
+    pub fn get_pad(&self) -> u16 {
+        self.pad
+    }
+
+    pub fn get_state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+
+    pub fn get_apply_freq(&self) -> bool {
+        self.apply_freq
+    }
+
+    pub fn get_best_of(&self) -> u32 {
+        self.best_of
+    }
+
+    pub fn get_other(&self) -> Option<&Path> {
+        self.other.as_deref()
+    }
+
+    pub fn get_output(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+
+    pub fn get_asymmetry(&self) -> i64 {
+        self.asymmetry
+    }
+
+    pub fn get_from_ntp(&self) -> bool {
+        self.from_ntp
+    }
+
+    pub fn get_from_unix(&self) -> bool {
+        self.from_unix
+    }
+
+    pub fn get_to_ntp(&self) -> bool {
+        self.to_ntp
+    }
+
+    pub fn get_format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn get_samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn get_min_valid_samples(&self) -> u32 {
+        self.min_valid_samples
+    }
+
+    pub fn get_sync_rtc(&self) -> bool {
+        self.sync_rtc
+    }
+
+    pub fn get_rtc_local(&self) -> bool {
+        self.rtc_local
+    }
+
+    pub fn get_save_response(&self) -> Option<&Path> {
+        self.save_response.as_deref()
+    }
+
+    pub fn get_from(&self) -> Option<&TimeStandard> {
+        self.from.as_ref()
+    }
+
+    pub fn get_to(&self) -> Option<&TimeStandard> {
+        self.to.as_ref()
+    }
+
+    pub fn get_max_spread(&self) -> Option<u64> {
+        self.max_spread
+    }
+
+    pub fn get_tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn get_show_timestamps(&self) -> bool {
+        self.show_timestamps
+    }
+
+    pub fn get_clamp_offset(&self) -> Option<u64> {
+        self.clamp_offset
+    }
+
+    pub fn get_compare_families(&self) -> bool {
+        self.compare_families
+    }
+
+    pub fn get_resolve_timeout(&self) -> Option<u64> {
+        self.resolve_timeout
+    }
+
+    pub fn get_timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+
+    pub fn get_max_step(&self) -> Option<u64> {
+        self.max_step
+    }
+
+    pub fn get_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn get_force(&self) -> bool {
+        self.force
+    }
+
+    pub fn get_dedup_by_subnet(&self) -> Option<(u8, u8)> {
+        self.dedup_by_subnet
+    }
+
+    pub fn get_weighting(&self) -> WeightingArg {
+        self.weighting
+    }
+
+    pub fn get_fail_on_any(&self) -> bool {
+        self.fail_on_any
+    }
+
+    pub fn get_extension_field(&self) -> Option<&ExtensionField> {
+        self.extension_field.as_ref()
+    }
+
+    pub fn get_settle(&self) -> Option<u64> {
+        self.settle
+    }
+
+    pub fn get_remeasure(&self) -> bool {
+        self.remeasure
+    }
+
+    pub fn get_matrix_format(&self) -> MatrixFormat {
+        self.matrix_format
+    }
+
+    pub fn get_sntp_fallback(&self) -> bool {
+        self.sntp_fallback
+    }
+
+    pub fn get_servers(&self) -> &[ConfiguredServer] {
+        &self.servers
+    }
+
+    pub fn get_ntp_port(&self) -> u16 {
+        self.ntp_port
+    }
+
+    pub fn get_ntp_version(&self) -> u8 {
+        self.ntp_version
+    }
+
+    pub fn get_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn get_max_deviation(&self) -> Option<f64> {
+        self.max_deviation
+    }
 }
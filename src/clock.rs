@@ -2,13 +2,56 @@ use chrono::{DateTime, Local, TimeZone};
 
 pub struct Clock;
 
+#[derive(Debug)]
+pub enum ClockError {
+    // The clock is controlled by the host (e.g. inside a container) and cannot be set here
+    #[cfg_attr(windows, allow(dead_code))]
+    HostManaged(std::io::Error),
+    // Any other failure reported by the operating system
+    Os(std::io::Error),
+}
+
+impl ClockError {
+    /// Classifies a failed attempt to set the clock. ENOSYS means the call is not available at
+    /// all, and EPERM while running as root means the capability to set the time was withheld;
+    /// both are what containers and other virtualized environments report.
+    #[cfg(not(windows))]
+    pub fn from_os_error(err: std::io::Error, is_root: bool) -> Self {
+        match err.raw_os_error() {
+            Some(libc::ENOSYS) => ClockError::HostManaged(err),
+            Some(libc::EPERM) if is_root => ClockError::HostManaged(err),
+            _ => ClockError::Os(err),
+        }
+    }
+}
+
+impl std::fmt::Display for ClockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClockError::HostManaged(_) => write!(
+                f,
+                "clock is managed by the host/container and cannot be set here"
+            ),
+            ClockError::Os(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClockError::HostManaged(err) | ClockError::Os(err) => Some(err),
+        }
+    }
+}
+
 impl Clock {
     pub fn get() -> DateTime<Local> {
         Local::now()
     }
 
     #[cfg(not(windows))]
-    pub fn set<TZ: TimeZone>(t: DateTime<TZ>) {
+    pub fn set<TZ: TimeZone>(t: DateTime<TZ>) -> Result<(), ClockError> {
         use libc::{settimeofday, timezone};
         use libc::{suseconds_t, time_t, timeval};
 
@@ -18,15 +61,184 @@ impl Clock {
         u.tv_sec = t.timestamp() as time_t;
         u.tv_usec = t.timestamp_subsec_micros() as suseconds_t;
 
-        unsafe {
+        let result = unsafe {
             let mock_tz: *const timezone = std::ptr::null();
 
-            settimeofday(&u as *const timeval, mock_tz);
+            settimeofday(&u as *const timeval, mock_tz)
+        };
+        if result != 0 {
+            let is_root = unsafe { libc::geteuid() } == 0;
+            return Err(ClockError::from_os_error(
+                std::io::Error::last_os_error(),
+                is_root,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds `ppm` parts per million to the kernel's clock frequency via adjtimex. The kernel
+    /// expresses the frequency in "scaled ppm": ppm with a 16-bit binary fraction, so 1 ppm is
+    /// 65536 units. The result is clamped to the kernel's limit of +/-500 ppm.
+    #[cfg(target_os = "linux")]
+    pub fn adjust_frequency(ppm: f64) -> Result<(), std::io::Error> {
+        use libc::{adjtimex, timex, ADJ_FREQUENCY};
+
+        const SCALED_PPM: f64 = 65536.0;
+        const MAX_FREQ: f64 = 500.0 * SCALED_PPM;
+
+        let mut tx: timex = unsafe { std::mem::zeroed() };
+        if unsafe { adjtimex(&mut tx) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let freq = (tx.freq as f64 + ppm * SCALED_PPM).clamp(-MAX_FREQ, MAX_FREQ);
+        tx = unsafe { std::mem::zeroed() };
+        tx.modes = ADJ_FREQUENCY;
+        tx.freq = freq as _;
+        if unsafe { adjtimex(&mut tx) } == -1 {
+            return Err(std::io::Error::last_os_error());
         }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn adjust_frequency(_ppm: f64) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "adjusting the clock frequency is only supported on Linux",
+        ))
     }
 
+    /// Writes the current system time to the hardware clock through /dev/rtc's RTC_SET_TIME
+    /// ioctl, like `hwclock --systohc`. The RTC conventionally keeps UTC; pass `local` for an
+    /// RTC that keeps local time (as dual-boot Windows machines often do).
+    #[cfg(target_os = "linux")]
+    pub fn sync_rtc(local: bool) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        use chrono::{Datelike, Timelike, Utc};
+
+        // struct rtc_time from <linux/rtc.h>
+        #[repr(C)]
+        struct RtcTime {
+            tm_sec: libc::c_int,
+            tm_min: libc::c_int,
+            tm_hour: libc::c_int,
+            tm_mday: libc::c_int,
+            tm_mon: libc::c_int,
+            tm_year: libc::c_int,
+            tm_wday: libc::c_int,
+            tm_yday: libc::c_int,
+            tm_isdst: libc::c_int,
+        }
+        // _IOW('p', 0x0a, struct rtc_time)
+        const RTC_SET_TIME: u32 = (1 << 30)
+            | ((std::mem::size_of::<RtcTime>() as u32) << 16)
+            | ((b'p' as u32) << 8)
+            | 0x0a;
+
+        let rtc = std::fs::File::open("/dev/rtc")
+            .or_else(|_| std::fs::File::open("/dev/rtc0"))
+            .map_err(|err| {
+                std::io::Error::new(err.kind(), format!("unable to open /dev/rtc: {}", err))
+            })?;
+
+        let now = if local {
+            Local::now().naive_local()
+        } else {
+            Utc::now().naive_utc()
+        };
+        let tm = RtcTime {
+            tm_sec: now.second() as libc::c_int,
+            tm_min: now.minute() as libc::c_int,
+            tm_hour: now.hour() as libc::c_int,
+            tm_mday: now.day() as libc::c_int,
+            tm_mon: now.month0() as libc::c_int,
+            tm_year: now.year() - 1900,
+            tm_wday: now.weekday().num_days_from_sunday() as libc::c_int,
+            tm_yday: now.ordinal0() as libc::c_int,
+            tm_isdst: 0,
+        };
+
+        if unsafe { libc::ioctl(rtc.as_raw_fd(), RTC_SET_TIME as _, &tm) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn sync_rtc(_local: bool) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "writing the hardware clock is only supported on Linux",
+        ))
+    }
+
+    /// Sets the system clock with SetSystemTime, which takes the time as UTC calendar fields.
+    /// It needs the SeSystemtimePrivilege, i.e. an elevated prompt.
     #[cfg(windows)]
-    fn set<tz: TimeZone>(t: Datetame<tz>) -> ! {
-        unimplemented!()
+    pub fn set<TZ: TimeZone>(t: DateTime<TZ>) -> Result<(), ClockError> {
+        use chrono::{Datelike, Timelike, Utc};
+        use windows::Win32::{Foundation::SYSTEMTIME, System::SystemInformation::SetSystemTime};
+
+        let t = t.with_timezone(&Utc);
+        let year = u16::try_from(t.year())
+            .ok()
+            .filter(|year| (1601..=30827).contains(year))
+            .ok_or_else(|| {
+                ClockError::Os(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("year {} cannot be set on Windows", t.year()),
+                ))
+            })?;
+        let st = SYSTEMTIME {
+            wYear: year,
+            wMonth: t.month() as u16,
+            wDayOfWeek: t.weekday().num_days_from_sunday() as u16,
+            wDay: t.day() as u16,
+            wHour: t.hour() as u16,
+            wMinute: t.minute() as u16,
+            // A leap second is reported as nanoseconds beyond 1e9; SYSTEMTIME has no room for it
+            wSecond: t.second() as u16,
+            wMilliseconds: t.timestamp_subsec_millis().min(999) as u16,
+        };
+
+        unsafe { SetSystemTime(&st) }.map_err(|err| ClockError::Os(err.into()))
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    fn classify(errno: i32, is_root: bool) -> ClockError {
+        ClockError::from_os_error(io::Error::from_raw_os_error(errno), is_root)
+    }
+
+    #[test]
+    fn from_os_error_recognizes_a_host_managed_clock() {
+        assert!(matches!(
+            classify(libc::ENOSYS, false),
+            ClockError::HostManaged(_)
+        ));
+        assert!(matches!(
+            classify(libc::ENOSYS, true),
+            ClockError::HostManaged(_)
+        ));
+        assert!(matches!(
+            classify(libc::EPERM, true),
+            ClockError::HostManaged(_)
+        ));
+    }
+
+    #[test]
+    fn from_os_error_passes_other_failures_through() {
+        assert!(matches!(classify(libc::EPERM, false), ClockError::Os(_)));
+        match classify(libc::EINVAL, true) {
+            ClockError::Os(err) => assert_eq!(err.raw_os_error(), Some(libc::EINVAL)),
+            other => panic!("expected an OS error, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,93 @@
+use std::{fs::File, io::IsTerminal, path::Path};
+
+use ntp_client::CheckSummary;
+
+const CHANGED: &str = "\x1b[33m";
+const MISSING: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+pub fn load(path: &Path) -> Result<CheckSummary, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let summary =
+        serde_json::from_reader(file).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(summary)
+}
+
+/// Prints the per-server and aggregate offsets of two runs side by side. Servers that only
+/// one run has are listed too, and changed values are highlighted when writing to a terminal.
+pub fn print_diff(before: &CheckSummary, after: &CheckSummary) {
+    let color = std::io::stdout().is_terminal();
+    let paint = |style: &str, text: String| {
+        if color {
+            format!("{}{}{}", style, text.trim_end(), RESET)
+        } else {
+            text
+        }
+    };
+
+    let mut hosts: Vec<&str> = before.servers.iter().map(|s| s.host.as_str()).collect();
+    for server in &after.servers {
+        if !hosts.contains(&server.host.as_str()) {
+            hosts.push(&server.host);
+        }
+    }
+    let width = hosts.iter().map(|h| h.len()).max().unwrap_or(0).max(9);
+    let offset_of = |summary: &CheckSummary, host: &str| {
+        summary
+            .servers
+            .iter()
+            .find(|s| s.host == host)
+            .map(|s| s.offset)
+    };
+
+    println!(
+        "{:width$}  {:>10}  {:>10}  {:>10}",
+        "server", "before", "after", "change"
+    );
+    for host in hosts {
+        let line = match (offset_of(before, host), offset_of(after, host)) {
+            (Some(Some(a)), Some(Some(b))) if a == b => {
+                format!("{:width$}  {:>8}ms  {:>8}ms  {:>10}", host, a, b, "")
+            }
+            (Some(Some(a)), Some(Some(b))) => paint(
+                CHANGED,
+                format!("{:width$}  {:>8}ms  {:>8}ms  {:>+8}ms", host, a, b, b - a),
+            ),
+            (a, b) => paint(
+                MISSING,
+                format!(
+                    "{:width$}  {:>10}  {:>10}  {:>10}",
+                    host,
+                    describe(a),
+                    describe(b),
+                    ""
+                ),
+            ),
+        };
+        println!("{}", line.trim_end());
+    }
+
+    let change = after.offset - before.offset;
+    let line = format!(
+        "{:width$}  {:>8.1}ms  {:>8.1}ms  {:>+8.1}ms",
+        "aggregate", before.offset, after.offset, change
+    );
+    println!(
+        "{}",
+        if change != 0.0 {
+            paint(CHANGED, line)
+        } else {
+            line
+        }
+    );
+}
+
+// Describes a server's offset in one run: its value, "failed" if it did not respond,
+// or "-" if the run did not query it at all
+fn describe(offset: Option<Option<i64>>) -> String {
+    match offset {
+        Some(Some(offset)) => format!("{}ms", offset),
+        Some(None) => "failed".to_string(),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use ntp_client::NTPTimestamp;
+
+// Number of seconds between 1 Jan 1900 (the NTP epoch) and 1 Jan 1970 (the UNIX epoch).
+// Bare numbers at or above this could be either kind of timestamp.
+const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
+
+pub enum Input {
+    // Decide from the value: an RFC3339 datetime or a small enough Unix timestamp
+    Auto,
+    Unix,
+    Ntp,
+}
+
+/// Converts `value` between Unix timestamps, NTP timestamps and RFC3339 datetimes.
+/// NTP timestamps are written as `seconds.fraction` with the raw 32-bit fraction, as on the
+/// wire. Without `to_ntp`, timestamps are printed as RFC3339 and datetimes as Unix timestamps.
+pub fn convert(value: &str, input: Input, to_ntp: bool) -> Result<String, String> {
+    let datetime = match input {
+        Input::Ntp => parse_ntp(value)?.into(),
+        Input::Unix => parse_unix(value)?,
+        Input::Auto => match DateTime::parse_from_rfc3339(value) {
+            Ok(t) if !to_ntp => return Ok(t.timestamp().to_string()),
+            Ok(t) => t.with_timezone(&Utc),
+            Err(_) => {
+                let datetime = parse_unix(value)?;
+                if datetime.timestamp() >= NTP_TO_UNIX_SECONDS {
+                    return Err(format!(
+                        "{} is ambiguous: it could be a Unix or an NTP timestamp; \
+                         use --from-unix or --from-ntp",
+                        value
+                    ));
+                }
+                datetime
+            }
+        },
+    };
+
+    if to_ntp {
+        let ntp = NTPTimestamp::try_from(datetime).map_err(|err| err.to_string())?;
+        Ok(format!("{}.{}", ntp.seconds, ntp.fraction))
+    } else {
+        Ok(datetime.to_rfc3339())
+    }
+}
+
+fn parse_unix(value: &str) -> Result<DateTime<Utc>, String> {
+    value
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| format!("Unable to parse {} as a datetime or timestamp", value))
+}
+
+fn parse_ntp(value: &str) -> Result<NTPTimestamp, String> {
+    let invalid = || {
+        format!(
+            "Unable to parse {} as an NTP timestamp (seconds.fraction)",
+            value
+        )
+    };
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, "0"));
+
+    Ok(NTPTimestamp {
+        seconds: seconds.parse().map_err(|_| invalid())?,
+        fraction: fraction.parse().map_err(|_| invalid())?,
+    })
+}
@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+
+use ntp_client::CheckSummary;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The most recent offsets of every server across polls, for drawing trends.
+pub struct OffsetHistory {
+    capacity: usize,
+    // Offsets in milliseconds, oldest first; None where the server gave no usable response
+    servers: Vec<(String, VecDeque<Option<i64>>)>,
+}
+
+impl OffsetHistory {
+    pub fn new(capacity: usize) -> Self {
+        OffsetHistory {
+            capacity,
+            servers: Vec::new(),
+        }
+    }
+
+    /// Appends every server's offset from `summary`, dropping the oldest beyond capacity.
+    pub fn record(&mut self, summary: &CheckSummary) {
+        for report in &summary.servers {
+            let index = match self
+                .servers
+                .iter()
+                .position(|(host, _)| *host == report.host)
+            {
+                Some(index) => index,
+                None => {
+                    self.servers.push((report.host.clone(), VecDeque::new()));
+                    self.servers.len() - 1
+                }
+            };
+            let offsets = &mut self.servers[index].1;
+            if offsets.len() == self.capacity {
+                offsets.pop_front();
+            }
+            offsets.push_back(report.offset);
+        }
+    }
+
+    /// Prints one sparkline per server, scaled to the range of that server's own offsets so
+    /// that small drifts remain visible. Missing responses are left blank.
+    pub fn print(&self) {
+        let width = self
+            .servers
+            .iter()
+            .map(|(host, _)| host.len())
+            .max()
+            .unwrap_or(0);
+
+        for (host, offsets) in &self.servers {
+            let known = offsets.iter().flatten();
+            let (min, max) = (known.clone().min(), known.max());
+            let line: String = offsets
+                .iter()
+                .map(|offset| match (offset, min, max) {
+                    (Some(offset), Some(min), Some(max)) if max > min => {
+                        let level = (offset - min) * (BARS.len() as i64 - 1) / (max - min);
+                        BARS[level as usize]
+                    }
+                    (Some(_), _, _) => BARS[BARS.len() / 2],
+                    (None, _, _) => ' ',
+                })
+                .collect();
+            let last = match offsets.back() {
+                Some(Some(offset)) => format!("{}ms", offset),
+                _ => "?".to_string(),
+            };
+            println!("{:width$}  {}  {}", host, line, last, width = width);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use ntp_client::{CheckSummary, QueryFailure, ServerReport};
+use serde::Serialize;
+
+/// The `--format json` record of one poll. Its fields are a stable interface for scripts:
+/// fields may be added, but existing ones are not renamed, removed or changed in meaning.
+#[derive(Serialize)]
+pub struct CheckReport<'a> {
+    // RFC3339 time at which the poll started
+    time: String,
+    // Weighted offset of the local clock in milliseconds; positive means the clock is behind.
+    // null if the poll failed
+    offset_ms: Option<f64>,
+    // Number of servers whose response was used
+    responders: usize,
+    servers: Vec<ServerEntry<'a>>,
+    // Why the poll failed as a whole; null when it succeeded
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ServerEntry<'a> {
+    host: &'a str,
+    // Offset and round-trip delay in milliseconds; null without a usable response
+    offset_ms: Option<i64>,
+    delay_ms: Option<i64>,
+    timed_out: bool,
+    // Why the response was not used; null when it was
+    error: Option<&'a str>,
+}
+
+impl<'a> CheckReport<'a> {
+    pub fn new(time: DateTime<Utc>, summary: &'a CheckSummary) -> Self {
+        CheckReport {
+            time: time.to_rfc3339(),
+            offset_ms: Some(summary.offset),
+            responders: summary.responses,
+            servers: entries(&summary.servers),
+            error: None,
+        }
+    }
+
+    /// The record of a poll that failed as a whole, with every server's result that the
+    /// failure carries.
+    pub fn failed(time: DateTime<Utc>, err: &'a std::io::Error) -> Self {
+        let servers = QueryFailure::of(err).map_or(&[][..], |failure| &failure.servers);
+        CheckReport {
+            time: time.to_rfc3339(),
+            offset_ms: None,
+            responders: 0,
+            servers: entries(servers),
+            error: Some(err.to_string()),
+        }
+    }
+
+    /// Writes the report as a single line, so that repeated polls form a JSON Lines stream.
+    pub fn write(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        serde_json::to_writer(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+fn entries(servers: &[ServerReport]) -> Vec<ServerEntry<'_>> {
+    servers
+        .iter()
+        .map(|server| ServerEntry {
+            host: &server.host,
+            offset_ms: server.offset,
+            delay_ms: server.delay,
+            timed_out: server.timed_out,
+            error: server.error.as_deref(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_poll_keeps_every_server() {
+        let server = ServerReport {
+            host: "127.0.0.1".to_string(),
+            offset: None,
+            delay: None,
+            error: Some("response took too long".to_string()),
+            timed_out: true,
+            version: 3,
+            low_confidence: false,
+            trailer_len: 0,
+            extension_fields: Vec::new(),
+            timestamps: None,
+            exchange: None,
+        };
+        let err = std::io::Error::other(QueryFailure {
+            message: "no NTP servers reachable".to_string(),
+            servers: vec![server],
+            notes: Vec::new(),
+            warnings: Vec::new(),
+        });
+
+        let mut out = Vec::new();
+        CheckReport::failed(Utc::now(), &err)
+            .write(&mut out)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["error"], "no NTP servers reachable");
+        assert!(json["offset_ms"].is_null());
+        assert_eq!(json["servers"][0]["host"], "127.0.0.1");
+        assert_eq!(json["servers"][0]["timed_out"], true);
+    }
+}
@@ -0,0 +1,306 @@
+//! Queries NTP servers and estimates how far the local clock is from their time.
+
+mod metrics;
+mod ntp;
+mod tls;
+
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc, time::Duration};
+
+pub use metrics::{MetricsSnapshot, NtpMetrics, ServerMetrics};
+
+pub use ntp::{
+    CheckSummary, ExtensionField, FamilyComparison, FamilyResult, NTPMessage, NTPResult,
+    NTPTimestamp, PacketField, QueryFailure, ReferenceReport, RelativeOffset, ServerReport,
+    Weighting, DEFAULT_SERVERS, PACKET_LAYOUT,
+};
+
+/// Queries a single server at `host`:`port` with the default options and returns its answer.
+/// The answer is returned even if the server reports an unsynchronized clock; check
+/// [`NTPResult::is_unsynchronized`] before trusting its offset.
+pub fn query(host: &str, port: u16) -> Result<NTPResult, std::io::Error> {
+    NtpClient::builder().port(port).build().query_server(host)
+}
+
+/// A configured set of NTP servers and query options. Create one with [`NtpClient::builder`].
+#[derive(Debug, Clone)]
+pub struct NtpClient {
+    pub(crate) servers: Vec<String>,
+    pub(crate) port: u16,
+    pub(crate) timeout: Duration,
+    pub(crate) robust: bool,
+    pub(crate) max_deviation: Option<f64>,
+    pub(crate) source_ports: Option<RangeInclusive<u16>>,
+    pub(crate) strict_source_port: bool,
+    pub(crate) pad: u16,
+    pub(crate) asymmetry: i64,
+    pub(crate) samples: u32,
+    pub(crate) min_valid_samples: u32,
+    pub(crate) save_response: Option<PathBuf>,
+    pub(crate) max_spread: Option<u64>,
+    pub(crate) tls: bool,
+    pub(crate) resolve_timeout: Option<Duration>,
+    pub(crate) metrics: Option<Arc<NtpMetrics>>,
+    pub(crate) dedup_subnet: Option<(u8, u8)>,
+    pub(crate) weighting: Weighting,
+    pub(crate) extension_field: Option<ExtensionField>,
+    pub(crate) sntp_fallback: bool,
+    pub(crate) version: u8,
+    pub(crate) server_versions: Vec<(String, u8)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NtpClientBuilder {
+    client: NtpClient,
+}
+
+impl NtpClient {
+    /// Returns a builder with the same defaults as the `clock` command line.
+    pub fn builder() -> NtpClientBuilder {
+        NtpClientBuilder {
+            client: NtpClient {
+                servers: DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect(),
+                port: 123,
+                timeout: Duration::from_secs(1),
+                robust: false,
+                max_deviation: None,
+                source_ports: None,
+                strict_source_port: false,
+                pad: 0,
+                asymmetry: 0,
+                samples: 1,
+                min_valid_samples: 1,
+                save_response: None,
+                max_spread: None,
+                tls: false,
+                resolve_timeout: None,
+                metrics: None,
+                dedup_subnet: None,
+                weighting: Weighting::Delay,
+                extension_field: None,
+                sntp_fallback: false,
+                version: 3,
+                server_versions: Vec::new(),
+            },
+        }
+    }
+
+    /// Queries every server and combines their offsets into one estimate. Nothing is printed:
+    /// the per-server results, notes and warnings are in the summary, or, if the query fails as
+    /// a whole, in the [`QueryFailure`] inside the error.
+    pub fn query(&self) -> Result<CheckSummary, std::io::Error> {
+        let result = ntp::check_time(self);
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&result);
+        }
+        result
+    }
+
+    /// Queries `host` alone, with this client's options, and returns its answer without
+    /// combining it with other servers.
+    pub fn query_server(&self, host: &str) -> Result<NTPResult, std::io::Error> {
+        ntp::sample_server(host, None, self)
+    }
+
+    /// Runs `rounds` full queries (at least one) and returns the 1-based number and summary of
+    /// the round whose combined delay was smallest, as the least disturbed estimate. Failed
+    /// rounds are skipped; if every round fails, the last error is returned.
+    pub fn query_best_of(&self, rounds: u32) -> Result<(u32, CheckSummary), std::io::Error> {
+        let mut best: Option<(u32, CheckSummary)> = None;
+        let mut last_err = None;
+
+        for round in 1..=rounds.max(1) {
+            match self.query() {
+                Ok(summary) => {
+                    if best.as_ref().is_none_or(|(_, b)| summary.delay < b.delay) {
+                        best = Some((round, summary));
+                    }
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        best.ok_or_else(|| last_err.expect("at least one round is run"))
+    }
+
+    /// Queries each server's IPv4 and IPv6 addresses separately, so that their offsets can be
+    /// compared. This always uses UDP.
+    pub fn compare_families(&self) -> Vec<FamilyComparison> {
+        ntp::compare_families(self)
+    }
+
+    /// The NTP version to query `host` with: its override if it has one, else the default.
+    pub(crate) fn version_for(&self, host: &str) -> u8 {
+        self.server_versions
+            .iter()
+            .find(|(server, _)| server == host)
+            .map_or(self.version, |&(_, version)| version)
+    }
+
+    /// Reports every other server's offset relative to the `reference` server's time. Fails
+    /// only if the reference gives no usable answer.
+    pub fn query_against(&self, reference: &str) -> Result<ReferenceReport, std::io::Error> {
+        ntp::check_against_reference(self, reference)
+    }
+}
+
+impl NtpClientBuilder {
+    pub fn servers<S: Into<String>>(mut self, servers: impl IntoIterator<Item = S>) -> Self {
+        self.client.servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.client.port = port;
+        self
+    }
+
+    /// How long to wait for each server's response.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    /// How long to wait for each server's name to resolve. By default resolution is not
+    /// bounded, as the system resolver has no timeout of its own.
+    pub fn resolve_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.client.resolve_timeout = timeout;
+        self
+    }
+
+    /// Query only the first server of each subnet, given as the (IPv4, IPv6) prefix lengths,
+    /// so that servers at the same site do not outweigh independent ones. A server with several
+    /// addresses is queried at one outside the subnets already taken, and only skipped if it has
+    /// none; skipped ones are listed in [`CheckSummary::notes`].
+    pub fn dedup_by_subnet(mut self, prefixes: Option<(u8, u8)>) -> Self {
+        self.client.dedup_subnet = prefixes;
+        self
+    }
+
+    /// How to weight each server's offset in the mean; see [`Weighting`] for the formulas.
+    pub fn weighting(mut self, weighting: Weighting) -> Self {
+        self.client.weighting = weighting;
+        self
+    }
+
+    /// Iteratively discard outlying offsets before averaging.
+    pub fn robust(mut self, robust: bool) -> Self {
+        self.client.robust = robust;
+        self
+    }
+
+    /// Discard offsets that lie more than `k` median absolute deviations from the median of
+    /// all offsets before averaging, with the median and deviation computed once. Together with
+    /// [`robust`](Self::robust), which removes outliers one at a time, it sets the threshold of
+    /// the robust filter instead (3 by default).
+    pub fn max_deviation(mut self, k: Option<f64>) -> Self {
+        self.client.max_deviation = k;
+        self
+    }
+
+    /// The NTP version (1 to 4) to send in requests to servers without their own version.
+    pub fn version(mut self, version: u8) -> Self {
+        self.client.version = version;
+        self
+    }
+
+    /// Query `host` with this NTP version instead of the default one.
+    pub fn server_version(mut self, host: impl Into<String>, version: u8) -> Self {
+        let host = host.into();
+        self.client
+            .server_versions
+            .retain(|(server, _)| *server != host);
+        self.client.server_versions.push((host, version));
+        self
+    }
+
+    /// Bind the local socket to a random port within this range.
+    pub fn source_port_range(mut self, range: Option<RangeInclusive<u16>>) -> Self {
+        self.client.source_ports = range;
+        self
+    }
+
+    /// Also reject responses whose source port differs from the queried port. By default only
+    /// the source address is checked, since NAT can legitimately rewrite the reply port. Only
+    /// in strict mode is a query to a closed port reported as refused; by default it shows as a
+    /// timeout.
+    pub fn strict_source_port(mut self, strict: bool) -> Self {
+        self.client.strict_source_port = strict;
+        self
+    }
+
+    /// Append this many zero bytes to each request. This is a network diagnostic for
+    /// middleboxes that treat packet sizes differently; the padded request is not a valid
+    /// NTP packet and strict servers may drop it.
+    pub fn pad(mut self, bytes: u16) -> Self {
+        self.client.pad = bytes;
+        self
+    }
+
+    /// Compensate for a known path asymmetry, in milliseconds: how much longer the outbound
+    /// (client to server) one-way delay is than the return delay. A slow uplink is positive.
+    /// Each offset is reduced by half of this value.
+    pub fn asymmetry(mut self, ms: i64) -> Self {
+        self.client.asymmetry = ms;
+        self
+    }
+
+    /// Query each server this many times and keep the sample with the lowest delay.
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.client.samples = samples.max(1);
+        self
+    }
+
+    /// Only use a server if at least this many of its samples succeeded.
+    pub fn min_valid_samples(mut self, min: u32) -> Self {
+        self.client.min_valid_samples = min;
+        self
+    }
+
+    /// Save the raw bytes of every response in this directory, one file per server.
+    pub fn save_response(mut self, dir: Option<PathBuf>) -> Self {
+        self.client.save_response = dir;
+        self
+    }
+
+    /// Fail instead of averaging when the servers' offsets span more than this many
+    /// milliseconds.
+    pub fn max_spread(mut self, ms: Option<u64>) -> Self {
+        self.client.max_spread = ms;
+        self
+    }
+
+    /// Experimental: exchange the NTP message over TLS on port 4460 instead of UDP. This needs
+    /// the `tls` feature. TLS overhead reduces timing precision, so results are marked as
+    /// low-confidence.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.client.tls = tls;
+        self
+    }
+
+    /// Record the outcome of every query in `metrics`, which may be shared with other clients
+    /// and read from other threads.
+    pub fn metrics(mut self, metrics: Arc<NtpMetrics>) -> Self {
+        self.client.metrics = Some(metrics);
+        self
+    }
+
+    /// Experimental: append this extension field to every UDP request, which is then sent as
+    /// NTPv4, and report whether each server echoes it. Servers may drop requests with unknown
+    /// or malformed extension fields instead of answering them.
+    pub fn extension_field(mut self, field: Option<ExtensionField>) -> Self {
+        self.client.extension_field = field;
+        self
+    }
+
+    /// Use responses from minimal servers that do not fill in the reference id or reference
+    /// timestamp, as long as their transmit timestamp is set. A missing receive timestamp is
+    /// then taken to equal the transmit timestamp. Such results are marked as low-confidence.
+    pub fn sntp_fallback(mut self, fallback: bool) -> Self {
+        self.client.sntp_fallback = fallback;
+        self
+    }
+
+    pub fn build(self) -> NtpClient {
+        self.client
+    }
+}
@@ -1,65 +1,686 @@
 mod cli;
 mod clock;
-mod ntp;
+mod compare;
+mod epoch;
+mod history;
+mod json;
+mod matrix;
+mod report;
+mod state;
+mod system_config;
 
-use std::time::Duration;
+use std::{
+    error::Error,
+    fmt,
+    io::{IsTerminal, Write},
+    path::Path,
+    time::Duration,
+};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveTime, TimeZone, Utc};
 use clap::Parser;
 
 use cli::*;
-use clock::Clock;
-use ntp::check_time;
+use clock::{Clock, ClockError};
+use history::OffsetHistory;
+use json::CheckReport;
+use matrix::OffsetMatrix;
+use ntp_client::{
+    CheckSummary, NtpClient, NtpClientBuilder, Weighting, DEFAULT_SERVERS, PACKET_LAYOUT,
+};
+use state::Measurement;
+use system_config::{read_system_servers, ConfiguredServer};
 
-fn main() {
+/// The error main fails with. A failing main's error is printed with Debug, so Debug shows the
+/// plain message like Display does.
+struct Failure(String);
+
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Failure {}
+
+fn fail(message: impl fmt::Display) -> Box<dyn Error> {
+    Box::new(Failure(message.to_string()))
+}
+
+fn parse_time(value: &str, std: &TimeStandard) -> Option<DateTime<FixedOffset>> {
+    match std {
+        TimeStandard::Rfc3339 => DateTime::parse_from_rfc3339(value).ok(),
+        TimeStandard::Rfc2822 => DateTime::parse_from_rfc2822(value).ok(),
+        TimeStandard::Timestamp => value
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|t| t.fixed_offset()),
+    }
+}
+
+fn format_time(t: &DateTime<FixedOffset>, std: &TimeStandard) -> String {
+    match std {
+        TimeStandard::Rfc3339 => t.to_rfc3339(),
+        TimeStandard::Rfc2822 => t.to_rfc2822(),
+        TimeStandard::Timestamp => t.timestamp().to_string(),
+    }
+}
+
+/// Combines the given date and/or time of day with the missing component taken from the
+/// current local clock. Both the inputs and the current clock are interpreted in local time.
+fn merge_with_now(date: Option<&str>, time: Option<&str>) -> Result<DateTime<Local>, String> {
+    let now = Clock::get();
+    let date = match date {
+        Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d")
+            .map_err(|_| format!("Unable to parse {} as a date (YYYY-MM-DD)", d))?,
+        None => now.date_naive(),
+    };
+    let time = match time {
+        Some(t) => NaiveTime::parse_from_str(t, "%H:%M:%S")
+            .map_err(|_| format!("Unable to parse {} as a time (HH:MM:SS)", t))?,
+        None => now.time(),
+    };
+
+    Local
+        .from_local_datetime(&date.and_time(time))
+        .single()
+        .ok_or_else(|| {
+            format!(
+                "{} {} is ambiguous or invalid in the local timezone",
+                date, time
+            )
+        })
+}
+
+/// Translates an offset into a plain-language verdict for the human-readable output.
+fn accuracy_class(offset: f64, good_ms: u64, fair_ms: u64) -> String {
+    let offset = offset.abs();
+    if offset <= good_ms as f64 {
+        format!("GOOD (offset within {}ms)", good_ms)
+    } else if offset <= fair_ms as f64 {
+        format!("FAIR (offset within {}ms)", fair_ms)
+    } else {
+        format!("POOR (offset beyond {}ms)", fair_ms)
+    }
+}
+
+/// Compares the offset with the one kept in the state file, writes the drift and the
+/// frequency correction that would cancel it to `out`, optionally applies it, and stores the offset.
+fn report_drift(
+    path: &Path,
+    offset: f64,
+    apply_freq: bool,
+    out: &mut dyn Write,
+) -> Result<(), std::io::Error> {
+    let current = Measurement {
+        at: Utc::now(),
+        offset,
+    };
+
+    match state::load(path)? {
+        Some(previous) => {
+            let ppm = current.ppm_since(&previous);
+            writeln!(
+                out,
+                "Drift: {:+.1}ms over {}s",
+                current.offset - previous.offset,
+                (current.at - previous.at).num_seconds()
+            )?;
+            writeln!(
+                out,
+                "Suggested frequency correction: {:+.3} ppm (adjtimex freq {:+.0})",
+                ppm,
+                ppm * 65536.0
+            )?;
+            if apply_freq {
+                Clock::adjust_frequency(ppm)?;
+                writeln!(out, "Applied frequency correction of {:+.3} ppm", ppm)?;
+            }
+        }
+        None => writeln!(out, "Drift: no previous measurement in {}", path.display())?,
+    }
+
+    state::save(path, &current)
+}
+
+/// Caps `offset` at `limit` milliseconds for display, returning whether it was capped.
+fn clamp_offset(offset: f64, limit: Option<u64>) -> (f64, bool) {
+    match limit {
+        Some(limit) if offset.abs() > limit as f64 => {
+            (offset.clamp(-(limit as f64), limit as f64), true)
+        }
+        _ => (offset, false),
+    }
+}
+
+/// The servers' time, given the local time `now` and the offset to the servers in
+/// milliseconds, along with the sign and size of the adjustment for display. A negative offset
+/// means the local clock is ahead of the servers.
+fn adjusted_now(now: DateTime<Utc>, offset_ms: i64) -> (DateTime<Utc>, &'static str, Duration) {
+    let adjust = Duration::from_millis(offset_ms.unsigned_abs());
+    if offset_ms < 0 {
+        (now - adjust, "-", adjust)
+    } else {
+        (now + adjust, "+", adjust)
+    }
+}
+
+/// The servers to query: those of the system configuration if requested, else the defaults.
+fn servers(args: &Cli) -> Result<Vec<ConfiguredServer>, String> {
+    if !args.get_servers().is_empty() {
+        Ok(args.get_servers().to_vec())
+    } else if args.get_from_system_config() {
+        read_system_servers()
+            .map_err(|err| format!("Unable to read the system NTP servers: {}", err))
+    } else {
+        Ok(DEFAULT_SERVERS
+            .iter()
+            .map(|s| ConfiguredServer {
+                host: s.to_string(),
+                version: None,
+            })
+            .collect())
+    }
+}
+
+/// A client builder for `servers` with every query option from the command line applied.
+fn client_builder(args: &Cli, servers: &[ConfiguredServer]) -> NtpClientBuilder {
+    let mut builder = NtpClient::builder().servers(servers.iter().map(|s| s.host.clone()));
+    for server in servers {
+        if let Some(version) = server.version {
+            builder = builder.server_version(&server.host, version);
+        }
+    }
+    builder
+        .port(args.get_ntp_port())
+        .version(args.get_ntp_version())
+        .robust(args.get_robust())
+        .max_deviation(args.get_max_deviation())
+        .source_port_range(args.get_source_port_range().cloned())
+        .strict_source_port(args.get_strict_source_port())
+        .pad(args.get_pad())
+        .asymmetry(args.get_asymmetry())
+        .samples(args.get_samples())
+        .min_valid_samples(args.get_min_valid_samples())
+        .save_response(args.get_save_response().map(Path::to_path_buf))
+        .max_spread(args.get_max_spread())
+        .tls(args.get_tls())
+        .timeout(Duration::from_millis(args.get_timeout_ms()))
+        .resolve_timeout(args.get_resolve_timeout().map(Duration::from_millis))
+        .dedup_by_subnet(args.get_dedup_by_subnet())
+        .extension_field(args.get_extension_field().cloned())
+        .sntp_fallback(args.get_sntp_fallback())
+        .weighting(match args.get_weighting() {
+            WeightingArg::Delay => Weighting::Delay,
+            WeightingArg::Combined => Weighting::Combined,
+        })
+}
+
+// Largest step sync takes without --force
+const SYNC_SANITY_LIMIT_MS: f64 = 3.0 * 3_600_000.0;
+
+/// Measures the offset, steps the clock by it and verifies the result with a second query to
+/// one of the servers that answered. The local clock is re-read immediately before stepping so
+/// that the time spent querying does not go stale. With --settle, it waits before stepping
+/// (the offset stays valid while waiting, as both clocks advance alike) and with --remeasure
+/// the offset is measured again afterwards. Steps beyond --max-step, or beyond
+/// `SYNC_SANITY_LIMIT_MS` unless --force is given, are refused. With --sync-rtc the stepped
+/// time is also written to the hardware clock. Returns the residual offset
+/// after the step, or None for a dry run.
+fn sync(args: &Cli) -> Result<Option<f64>, String> {
+    let servers = servers(args)?;
+    let client = client_builder(args, &servers).build();
+    let measure = || {
+        let result = client.query();
+        report::print_outcome(result.as_ref(), args);
+        result.map_err(|err| err.to_string())
+    };
+
+    let mut summary = measure()?;
+    println!("Measured offset: {:+.1}ms", summary.offset);
+    if let Some(settle) = args.get_settle() {
+        std::thread::sleep(Duration::from_millis(settle));
+        if args.get_remeasure() {
+            summary = measure()?;
+            println!(
+                "Measured offset after settling {}ms: {:+.1}ms",
+                settle, summary.offset
+            );
+        }
+    }
+
+    let offset = summary.offset;
+    if let Some(max_step) = args.get_max_step() {
+        if offset.abs() > max_step as f64 {
+            return Err(format!(
+                "offset {:+.1}ms exceeds --max-step {}ms; not stepping",
+                offset, max_step
+            ));
+        }
+    }
+    if offset.abs() > SYNC_SANITY_LIMIT_MS && !args.get_force() {
+        return Err(format!(
+            "offset {:+.1}ms is beyond {} hours, which more likely means a broken server than a \
+             wrong clock; not stepping without --force",
+            offset,
+            SYNC_SANITY_LIMIT_MS / 3_600_000.0
+        ));
+    }
+    if args.get_dry_run() {
+        println!("Dry run: would step the clock by {:+.1}ms", offset);
+        return Ok(None);
+    }
+
+    let step = chrono::Duration::microseconds((offset * 1000.0) as i64);
+    Clock::set(Clock::get() + step).map_err(|err| format!("Unable to set the time: {}", err))?;
+    println!("Stepped the clock by {:+.1}ms", offset);
+    if args.get_sync_rtc() {
+        Clock::sync_rtc(args.get_rtc_local())
+            .map_err(|err| format!("Unable to write the hardware clock: {}", err))?;
+    }
+
+    let verifier = summary
+        .servers
+        .iter()
+        .find(|server| server.offset.is_some())
+        .map(|server| server.host.clone())
+        .expect("at least one server responded");
+    let verification = client_builder(args, &servers)
+        .servers([verifier.clone()])
+        .build()
+        .query();
+    report::print_outcome(verification.as_ref(), args);
+    let residual = verification
+        .map_err(|err| format!("Unable to verify the step with {}: {}", verifier, err))?
+        .offset;
+    println!(
+        "Residual offset: {:+.1}ms (verified with {})",
+        residual, verifier
+    );
+    Ok(Some(residual))
+}
+
+/// Prints the NTP header layout as a reference for reading raw responses, such as those saved
+/// with --save-response.
+fn explain_packet() {
+    let name_width = PACKET_LAYOUT
+        .iter()
+        .map(|f| f.name.len())
+        .max()
+        .unwrap_or(0);
+    let usage_width = PACKET_LAYOUT
+        .iter()
+        .map(|f| f.usage.len())
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:>6}  {:7}  {:name_width$}  {:usage_width$}  Meaning",
+        "Offset", "Size", "Field", "Use"
+    );
+    for field in PACKET_LAYOUT {
+        println!(
+            "{:>6}  {:7}  {:name_width$}  {:usage_width$}  {}",
+            field.offset, field.size, field.name, field.usage, field.meaning
+        );
+    }
+    println!();
+    println!(
+        "Multi-byte fields are big-endian. An NTP timestamp is 32-bit seconds since \
+         1900-01-01T00:00:00Z followed by a 32-bit fraction of a second (units of 2^-32 s)."
+    );
+}
+
+/// Writes t1 to t4 of every server that responded as raw NTP timestamps, with the datetime
+/// each converts to alongside.
+fn print_timestamps(summary: &CheckSummary, out: &mut dyn Write) -> Result<(), std::io::Error> {
+    for server in &summary.servers {
+        let Some(timestamps) = server.timestamps else {
+            continue;
+        };
+        writeln!(out, "{}:", server.host)?;
+        for (i, ts) in timestamps.iter().enumerate() {
+            let datetime: DateTime<Utc> = (*ts).into();
+            writeln!(
+                out,
+                "  t{} {}.{} ({})",
+                i + 1,
+                ts.seconds,
+                ts.fraction,
+                datetime.to_rfc3339()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Where check-ntp writes extra detail such as timestamps and drift: stdout for human output,
+/// but stderr in the other formats, where only the offset (or matrix, or JSON) belongs on stdout.
+fn diagnostic_out(format: OutputFormat) -> Box<dyn Write> {
+    match format {
+        OutputFormat::Human => Box::new(std::io::stdout()),
+        OutputFormat::Raw | OutputFormat::Matrix | OutputFormat::Json => {
+            Box::new(std::io::stderr())
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
     let action = args.get_action();
     let std = args.get_std();
     let datetime = args.get_datetime();
+    let date = args.get_date();
+    let time = args.get_time();
+    let format = args.get_format();
 
     match action {
         Action::Get => {
-            let now = Clock::get();
+            let now = format_time(&Clock::get().fixed_offset(), std);
             match std {
-                TimeStandard::Rfc3339 => println!("RFC3339: {}", now.to_rfc3339()),
-                TimeStandard::Rfc2822 => println!("RCF2822: {}", now.to_rfc2822()),
-                TimeStandard::Timestamp => println!("{}", now.timestamp()),
+                TimeStandard::Rfc3339 => println!("RFC3339: {}", now),
+                TimeStandard::Rfc2822 => println!("RCF2822: {}", now),
+                TimeStandard::Timestamp => println!("{}", now),
             }
         }
         Action::Set => {
-            let t_ = datetime.unwrap();
-            let t = match std {
-                TimeStandard::Rfc3339 => DateTime::parse_from_rfc3339(&t_),
-                TimeStandard::Rfc2822 => DateTime::parse_from_rfc2822(&t_),
-                _ => unimplemented!(),
+            let t = if date.is_some() || time.is_some() {
+                merge_with_now(date, time).map_err(fail)?.fixed_offset()
+            } else {
+                let t_ = datetime.ok_or_else(|| fail("set needs a time to set"))?;
+                parse_time(t_, std)
+                    .ok_or_else(|| fail(format!("Unable to parse {} as {:?}", t_, std)))?
             };
-            let t = match t {
-                Ok(t) => t,
-                Err(_) => {
-                    eprintln!("error: Unable to parse {} as {:?}", t_, std);
-                    return;
+            match Clock::set(t) {
+                Ok(()) => (),
+                Err(err @ ClockError::HostManaged(_)) => return Err(fail(err)),
+                Err(ClockError::Os(err)) => {
+                    return Err(fail(format!("Unable to set the time: {}", err)))
                 }
-            };
-            Clock::set(t);
-
-            let maybe_error = std::io::Error::last_os_error();
-            let os_error_code = maybe_error.raw_os_error();
-            match os_error_code {
-                Some(0) => (),
-                Some(_) => eprintln!("Unable to set the time: {:?}", maybe_error),
-                None => (),
+            }
+
+            if args.get_sync_rtc() {
+                Clock::sync_rtc(args.get_rtc_local())
+                    .map_err(|err| fail(format!("Unable to write the hardware clock: {}", err)))?;
             }
         }
         Action::CheckNtp => {
-            let offset = check_time().unwrap() as isize;
-            let adjust = Duration::from_millis(offset as u64);
-            let now = if offset.is_positive() {
-                Utc::now() + adjust
+            if args.get_min_valid_samples() > args.get_samples() {
+                return Err(fail(
+                    "--min-valid-samples must not be larger than --samples",
+                ));
+            }
+            if args.get_good_ms() > args.get_fair_ms() {
+                return Err(fail("--good-ms must not be larger than --fair-ms"));
+            }
+
+            let servers = servers(&args).map_err(fail)?;
+            let client = client_builder(&args, &servers).build();
+
+            if args.get_compare_families() {
+                report::print_families(&client.compare_families());
+                return Ok(());
+            }
+
+            if let Some(reference) = args.get_reference() {
+                let against = client.query_against(reference).map_err(|err| {
+                    fail(format!("Unable to query reference {}: {}", reference, err))
+                })?;
+                report::print_reference(&against);
+                return Ok(());
+            }
+
+            let mut failed = false;
+            // A single poll that fails is the error itself; repeated polls log it and carry on
+            let mut poll_error = None;
+            let mut previous: Option<f64> = None;
+            // Trends are only drawn when polling repeatedly for someone watching a terminal
+            const SPARKLINE_POLLS: usize = 20;
+            let mut history = (args.get_count() > 1 && std::io::stdout().is_terminal())
+                .then(|| OffsetHistory::new(SPARKLINE_POLLS));
+            let mut matrix = OffsetMatrix::default();
+
+            for poll in 1..=args.get_count() {
+                if poll > 1 {
+                    std::thread::sleep(Duration::from_secs(args.get_interval()));
+                }
+
+                let polled_at = Utc::now();
+                let outcome = client.query_best_of(args.get_best_of());
+                report::print_outcome(outcome.as_ref().map(|(_, summary)| summary), &args);
+                let summary = match outcome {
+                    Ok((round, summary)) => {
+                        if args.get_best_of() > 1 {
+                            eprintln!(
+                                "Selected round {} of {} (delay {:.1}ms, {} servers, offset {:.1}ms)",
+                                round,
+                                args.get_best_of(),
+                                summary.delay,
+                                summary.responses,
+                                summary.offset
+                            );
+                        }
+                        summary
+                    }
+                    Err(err) => {
+                        if let OutputFormat::Json = format {
+                            let report = CheckReport::failed(polled_at, &err);
+                            if let Err(err) = report.write(&mut std::io::stdout()) {
+                                eprintln!("error: {}", err);
+                            }
+                        }
+                        matrix.record(polled_at, None);
+                        if args.get_count() == 1 {
+                            poll_error = Some(err);
+                        } else {
+                            eprintln!("error: {}", err);
+                            failed = true;
+                        }
+                        continue;
+                    }
+                };
+                matrix.record(polled_at, Some(&summary));
+                // Everything past the display below works with the unclamped offset
+                let (shown, clamped) = clamp_offset(summary.offset, args.get_clamp_offset());
+                let (now, sign, adjust) = adjusted_now(Utc::now(), shown as i64);
+                match format {
+                    OutputFormat::Human => {
+                        if clamped {
+                            println!("{now}  ({sign}{:?}, clamped)", adjust);
+                        } else {
+                            println!("{now}  ({sign}{:?})", adjust);
+                        }
+                        println!(
+                            "Clock accuracy: {}",
+                            accuracy_class(summary.offset, args.get_good_ms(), args.get_fair_ms())
+                        );
+                        if let Some(history) = history.as_mut() {
+                            history.record(&summary);
+                            history.print();
+                        }
+                    }
+                    // The matrix is written once all polls are done
+                    OutputFormat::Matrix => (),
+                    OutputFormat::Json => {
+                        let report = CheckReport::new(polled_at, &summary);
+                        if let Err(err) = report.write(&mut std::io::stdout()) {
+                            eprintln!("error: {}", err);
+                            failed = true;
+                        }
+                    }
+                    OutputFormat::Raw => {
+                        println!("{:.3}", shown);
+                        if clamped {
+                            eprintln!(
+                                "warning: offset {:.3}ms clamped for display",
+                                summary.offset
+                            );
+                        }
+                    }
+                }
+
+                if let Some(path) = args.get_output() {
+                    let written = std::fs::File::create(path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|file| {
+                            serde_json::to_writer_pretty(file, &summary)
+                                .map_err(|err| err.to_string())
+                        });
+                    if let Err(err) = written {
+                        eprintln!("error: Unable to write {}: {}", path.display(), err);
+                        failed = true;
+                    }
+                }
+
+                if args.get_show_timestamps() {
+                    let mut out = diagnostic_out(format);
+                    if let Err(err) = print_timestamps(&summary, &mut out) {
+                        eprintln!("error: {}", err);
+                        failed = true;
+                    }
+                }
+
+                if let Some(path) = args.get_state_file() {
+                    let mut out = diagnostic_out(format);
+                    if let Err(err) =
+                        report_drift(path, summary.offset, args.get_apply_freq(), &mut out)
+                    {
+                        eprintln!("error: {}", err);
+                        failed = true;
+                    }
+                }
+
+                if args.get_fail_on_any() {
+                    let failures: Vec<String> = summary
+                        .servers
+                        .iter()
+                        .filter_map(|server| {
+                            let err = server.error.as_ref()?;
+                            Some(format!("{} ({})", server.host, err))
+                        })
+                        .collect();
+                    if !failures.is_empty() {
+                        eprintln!(
+                            "error: {} of {} server(s) failed: {}",
+                            failures.len(),
+                            summary.servers.len(),
+                            failures.join(", ")
+                        );
+                        failed = true;
+                    }
+                }
+
+                if args.get_warn_if_unsynced() && !summary.unsynced.is_empty() {
+                    eprintln!(
+                        "warning: excluded unsynchronized server(s): {}",
+                        summary.unsynced.join(", ")
+                    );
+                    failed = true;
+                }
+
+                if let (Some(max_delta), Some(previous)) = (args.get_max_delta_per_poll(), previous)
+                {
+                    let delta = summary.offset - previous;
+                    if delta.abs() > max_delta as f64 {
+                        eprintln!(
+                            "warning: offset changed by {:.0}ms at poll {} (limit {}ms)",
+                            delta, poll, max_delta
+                        );
+                        failed = true;
+                    }
+                }
+                previous = Some(summary.offset);
+            }
+
+            if let OutputFormat::Matrix = format {
+                let mut out = std::io::stdout();
+                let written = match args.get_matrix_format() {
+                    MatrixFormat::Csv => matrix.write_csv(&mut out),
+                    MatrixFormat::Json => matrix.write_json(&mut out),
+                };
+                if let Err(err) = written {
+                    eprintln!("error: {}", err);
+                    failed = true;
+                }
+            }
+
+            if let Some(err) = poll_error {
+                return Err(fail(err));
+            }
+            if failed {
+                return Err(fail("one or more checks failed"));
+            }
+        }
+        Action::Sync => match sync(&args).map_err(fail)? {
+            Some(residual) if residual.abs() > args.get_good_ms() as f64 => {
+                return Err(fail(format!(
+                    "residual offset {:+.1}ms is beyond {}ms; the sync did not take",
+                    residual,
+                    args.get_good_ms()
+                )));
+            }
+            Some(_) => println!("Sync verified"),
+            None => (),
+        },
+        Action::ExplainPacket => explain_packet(),
+        Action::CompareRuns => {
+            let (Some(before), Some(after)) = (datetime, args.get_other()) else {
+                return Err(fail("compare-runs needs two result files"));
+            };
+            let before = compare::load(Path::new(before)).map_err(fail)?;
+            let after = compare::load(after).map_err(fail)?;
+            compare::print_diff(&before, &after);
+        }
+        Action::Epoch => {
+            let Some(value) = datetime else {
+                return Err(fail("epoch needs a value to convert"));
+            };
+            let input = if args.get_from_ntp() {
+                epoch::Input::Ntp
+            } else if args.get_from_unix() {
+                epoch::Input::Unix
             } else {
-                Utc::now() - adjust
+                epoch::Input::Auto
+            };
+            let converted = epoch::convert(value, input, args.get_to_ntp()).map_err(fail)?;
+            println!("{}", converted);
+        }
+        Action::Format => {
+            let (Some(value), Some(from), Some(to)) = (datetime, args.get_from(), args.get_to())
+            else {
+                return Err(fail("format needs --from, --to and a value to convert"));
             };
-            let sign = if offset.is_positive() { "+" } else { "-" };
-            println!("{now}  ({sign}{:?})", adjust);
+            let t = parse_time(value, from)
+                .ok_or_else(|| fail(format!("Unable to parse {} as {:?}", value, from)))?;
+            println!("{}", format_time(&t, to));
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjusted_now_applies_the_offset() {
+        let now = "2024-05-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let (ahead, sign, adjust) = adjusted_now(now, 1500);
+        assert_eq!(ahead, now + Duration::from_millis(1500));
+        assert_eq!((sign, adjust), ("+", Duration::from_millis(1500)));
+
+        let (behind, sign, adjust) = adjusted_now(now, -250);
+        assert_eq!(behind, now - Duration::from_millis(250));
+        assert_eq!((sign, adjust), ("-", Duration::from_millis(250)));
+
+        assert_eq!(adjusted_now(now, 0), (now, "+", Duration::ZERO));
+    }
 }
@@ -0,0 +1,67 @@
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use ntp_client::CheckSummary;
+use serde::Serialize;
+
+/// Every server's offset at every poll of a run: one row per poll (time), one column per
+/// server, in the order the servers were first seen. Cells without a usable response are empty.
+#[derive(Default, Serialize)]
+pub struct OffsetMatrix {
+    servers: Vec<String>,
+    rows: Vec<Row>,
+}
+
+#[derive(Serialize)]
+struct Row {
+    // RFC3339 time at which the poll started
+    time: String,
+    // Offsets in milliseconds, one per entry of `servers`
+    offsets: Vec<Option<i64>>,
+}
+
+impl OffsetMatrix {
+    /// Adds the row of a poll at `time`; a failed poll (None) adds a row of empty cells.
+    pub fn record(&mut self, time: DateTime<Utc>, summary: Option<&CheckSummary>) {
+        let mut offsets = vec![None; self.servers.len()];
+        for report in summary.iter().flat_map(|summary| &summary.servers) {
+            let column = match self.servers.iter().position(|host| *host == report.host) {
+                Some(column) => column,
+                None => {
+                    // Earlier rows get an empty cell for the new column
+                    for row in &mut self.rows {
+                        row.offsets.push(None);
+                    }
+                    self.servers.push(report.host.clone());
+                    offsets.push(None);
+                    self.servers.len() - 1
+                }
+            };
+            offsets[column] = report.offset;
+        }
+        self.rows.push(Row {
+            time: time.to_rfc3339(),
+            offsets,
+        });
+    }
+
+    /// Writes the matrix as CSV with a `time` column followed by one column per server.
+    pub fn write_csv(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "time,{}", self.servers.join(","))?;
+        for row in &self.rows {
+            let cells: Vec<String> = row
+                .offsets
+                .iter()
+                .map(|offset| offset.map(|o| o.to_string()).unwrap_or_default())
+                .collect();
+            writeln!(out, "{},{}", row.time, cells.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the matrix as JSON: `{"servers": [...], "rows": [{"time", "offsets": [...]}]}`.
+    pub fn write_json(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::CheckSummary;
+
+/// Running totals over every query made by the clients it is attached to with
+/// [`NtpClientBuilder::metrics`](crate::NtpClientBuilder::metrics). It can be shared between
+/// threads and read at any time with [`NtpMetrics::snapshot`].
+#[derive(Debug, Default)]
+pub struct NtpMetrics {
+    state: Mutex<MetricsSnapshot>,
+}
+
+/// The state of an [`NtpMetrics`] at one point in time.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    // Number of queries made, successful or not
+    pub polls: u64,
+    // Number of queries that produced a combined offset
+    pub successes: u64,
+    // Number of queries that failed as a whole
+    pub failures: u64,
+    // Smallest, largest and mean combined offset in milliseconds over the successful queries
+    pub min_offset: Option<f64>,
+    pub max_offset: Option<f64>,
+    pub mean_offset: Option<f64>,
+    // When the last successful query finished
+    pub last_success: Option<DateTime<Utc>>,
+    // Per-server counts, in the order the servers were first seen
+    pub servers: Vec<ServerMetrics>,
+}
+
+/// How often a single server contributed to a query.
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+    pub host: String,
+    // Queries in which the server's response was used
+    pub successes: u64,
+    // Queries in which the server did not respond usefully
+    pub failures: u64,
+}
+
+impl NtpMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the current totals.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Adds the outcome of one query.
+    pub(crate) fn record(&self, result: &Result<CheckSummary, std::io::Error>) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.polls += 1;
+
+        let summary = match result {
+            Ok(summary) => summary,
+            Err(_) => {
+                state.failures += 1;
+                return;
+            }
+        };
+
+        if summary.responses > 0 {
+            let previous = state.successes as f64;
+            state.successes += 1;
+            state.min_offset = Some(
+                state
+                    .min_offset
+                    .map_or(summary.offset, |o| o.min(summary.offset)),
+            );
+            state.max_offset = Some(
+                state
+                    .max_offset
+                    .map_or(summary.offset, |o| o.max(summary.offset)),
+            );
+            state.mean_offset = Some(
+                (state.mean_offset.unwrap_or(0.0) * previous + summary.offset) / (previous + 1.0),
+            );
+            state.last_success = Some(Utc::now());
+        } else {
+            state.failures += 1;
+        }
+    }
+
+    /// Adds whether each server's response was used in one query. This is recorded separately
+    /// from [`record`](Self::record), because servers are counted even when the query as a
+    /// whole fails.
+    pub(crate) fn record_servers<'a>(&self, outcomes: impl IntoIterator<Item = (&'a str, bool)>) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+
+        for (host, used) in outcomes {
+            let index = match state.servers.iter().position(|s| s.host == host) {
+                Some(index) => index,
+                None => {
+                    state.servers.push(ServerMetrics {
+                        host: host.to_string(),
+                        ..Default::default()
+                    });
+                    state.servers.len() - 1
+                }
+            };
+            let server = &mut state.servers[index];
+            if used {
+                server.successes += 1;
+            } else {
+                server.failures += 1;
+            }
+        }
+    }
+}
@@ -1,25 +1,215 @@
-use std::{net::UdpSocket, time::Duration};
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    ops::RangeInclusive,
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::Instant,
+};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use chrono::{DateTime, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{tls, NtpClient};
 
 const NTP_MESSAGE_LENGTH: usize = 48;
+// Room for a response with a trailer after the header, such as the key id and MAC of
+// symmetric-key authentication; anything longer than this is cut off
+const RECV_BUFFER_LENGTH: usize = 1024;
 // Number of seconds between 1 Jan 1900(the NTP epoch) and 1 Jan 1970 (the UNIX epoch)
 const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
-const LOCAL_ADDR: &'static str = "0.0.0.0:12300";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+pub const DEFAULT_SERVERS: [&str; 5] = [
+    "time.nist.gov",
+    "time.apple.com",
+    "time.euro.apple.com",
+    "time.google.com",
+    "time2.google.com",
+];
+
+// Byte offsets of the header fields this crate reads
+const REF_ID_OFFSET: usize = 12;
+const REF_TIME_OFFSET: usize = 16;
+const RX_TIME_OFFSET: usize = 32;
+const TX_TIME_OFFSET: usize = 40;
+
+/// One field of the 48-byte NTP header.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketField {
+    // Byte offset of the field (fields sharing a byte have the same offset)
+    pub offset: usize,
+    pub size: &'static str,
+    pub name: &'static str,
+    pub meaning: &'static str,
+    // What this crate does with the field
+    pub usage: &'static str,
+}
+
+/// The layout of the NTP header in wire order. Timestamps are 32-bit seconds since
+/// 1900-01-01 followed by a 32-bit binary fraction of a second, both big-endian.
+pub const PACKET_LAYOUT: [PacketField; 13] = [
+    PacketField {
+        offset: 0,
+        size: "2 bits",
+        name: "LI",
+        meaning: "leap indicator: 0 none, 1 +1s, 2 -1s, 3 clock unsynchronized",
+        usage: "read",
+    },
+    PacketField {
+        offset: 0,
+        size: "3 bits",
+        name: "VN",
+        meaning: "protocol version",
+        usage: "written (3 unless configured; 4 with extension fields), read (--verbose)",
+    },
+    PacketField {
+        offset: 0,
+        size: "3 bits",
+        name: "Mode",
+        meaning: "3 client, 4 server",
+        usage: "written (3), checked (4)",
+    },
+    PacketField {
+        offset: 1,
+        size: "1 byte",
+        name: "Stratum",
+        meaning: "0 unspecified, 1 primary reference, 2-15 hops from one",
+        usage: "checked (1-15), read (--weighting combined)",
+    },
+    PacketField {
+        offset: 2,
+        size: "1 byte",
+        name: "Poll",
+        meaning: "maximum poll interval, log2 seconds (signed)",
+        usage: "-",
+    },
+    PacketField {
+        offset: 3,
+        size: "1 byte",
+        name: "Precision",
+        meaning: "server clock precision, log2 seconds (signed)",
+        usage: "-",
+    },
+    PacketField {
+        offset: 4,
+        size: "4 bytes",
+        name: "Root Delay",
+        meaning: "round trip to the reference clock, 16.16 fixed-point seconds",
+        usage: "-",
+    },
+    PacketField {
+        offset: 8,
+        size: "4 bytes",
+        name: "Root Dispersion",
+        meaning: "error relative to the reference clock, 16.16 fixed-point seconds",
+        usage: "-",
+    },
+    PacketField {
+        offset: REF_ID_OFFSET,
+        size: "4 bytes",
+        name: "Reference ID",
+        meaning: "ASCII source code at stratum 1, else upstream IPv4 address or IPv6 hash",
+        usage: "checked (nonzero), read (kiss code at stratum 0)",
+    },
+    PacketField {
+        offset: REF_TIME_OFFSET,
+        size: "8 bytes",
+        name: "Reference Timestamp",
+        meaning: "when the server clock was last set, NTP timestamp",
+        usage: "checked (nonzero)",
+    },
+    PacketField {
+        offset: 24,
+        size: "8 bytes",
+        name: "Origin Timestamp",
+        meaning: "client transmit time echoed by the server, NTP timestamp",
+        usage: "-",
+    },
+    PacketField {
+        offset: RX_TIME_OFFSET,
+        size: "8 bytes",
+        name: "Receive Timestamp",
+        meaning: "when the server received the request, NTP timestamp",
+        usage: "read (t2)",
+    },
+    PacketField {
+        offset: TX_TIME_OFFSET,
+        size: "8 bytes",
+        name: "Transmit Timestamp",
+        meaning: "when the server sent the response, NTP timestamp",
+        usage: "read (t3), checked (nonzero)",
+    },
+];
+
+/// An NTPv4 extension field (RFC 7822), carried between the header and any MAC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    // The value without the padding that the wire format adds
+    pub value: Vec<u8>,
+}
+
+impl ExtensionField {
+    // The smallest last extension field RFC 7822 allows in a packet without a MAC, including
+    // the 4-byte type and length. Anything shorter could be 20 or 24 bytes long and would be
+    // read as a key id and MAC.
+    const MIN_LAST_LENGTH: usize = 28;
+
+    /// Encodes the field as type, length and the value zero-padded to a multiple of 4 bytes,
+    /// as the last field of a packet without a MAC.
+    fn encode(&self) -> Vec<u8> {
+        let length = (4 + self.value.len())
+            .next_multiple_of(4)
+            .max(Self::MIN_LAST_LENGTH);
+        let mut data = Vec::with_capacity(length);
+        data.extend_from_slice(&self.field_type.to_be_bytes());
+        data.extend_from_slice(&(length as u16).to_be_bytes());
+        data.extend_from_slice(&self.value);
+        data.resize(length, 0);
+        data
+    }
+
+    /// Decodes the extension fields at the start of `data`, the bytes after the header. A key
+    /// id and MAC (20 or 24 bytes) or anything that does not form a valid field ends the list.
+    fn decode_all(mut data: &[u8]) -> Vec<ExtensionField> {
+        let mut fields = Vec::new();
+        while data.len() >= 4 && data.len() != 20 && data.len() != 24 {
+            let field_type = u16::from_be_bytes([data[0], data[1]]);
+            let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+            if length < 4 || !length.is_multiple_of(4) || length > data.len() {
+                break;
+            }
+            fields.push(ExtensionField {
+                field_type,
+                value: data[4..length].to_vec(),
+            });
+            data = &data[length..];
+        }
+        fields
+    }
+}
 
-#[derive(Debug, Default, Copy, Clone)]
-struct NTPTimestamp {
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct NTPTimestamp {
     //NTP timestamps are expressed as 32-bit seconds and fractional fractions.
-    seconds: u32,
-    fraction: u32,
+    pub seconds: u32,
+    pub fraction: u32,
 }
 
-struct NTPMessage {
+/// The 48-byte NTP header of a request or response.
+#[derive(Debug, Clone)]
+pub struct NTPMessage {
     data: [u8; NTP_MESSAGE_LENGTH],
 }
 
-struct NTPResult {
+/// One server's answer to a request, with the four timestamps of the exchange.
+#[derive(Debug, Clone)]
+pub struct NTPResult {
     // t1 is the local computer's record of the time when the first message is transmitted
     t1: DateTime<Utc>,
     // t2 is recorded by the remote server at the time that the first message is received.
@@ -28,65 +218,393 @@ struct NTPResult {
     t3: DateTime<Utc>,
     // t4 is the local computer's record of the time when the second message is received.
     t4: DateTime<Utc>,
+    // leap_indicator is the LI field of the server's response.
+    leap_indicator: u8,
+    // stratum is the server's distance from a reference clock (1 is a primary server).
+    stratum: u8,
+    // version is the NTP version of the request.
+    version: u8,
+    // asymmetry is how much longer the outbound path takes than the return path.
+    asymmetry: chrono::Duration,
+    // low_confidence says why the result is less precise than usual, e.g. TLS overhead.
+    low_confidence: Option<&'static str>,
+    // trailer_len is the number of bytes that followed the 48-byte header (e.g. a MAC).
+    trailer_len: usize,
+    // extensions are the extension fields found in the trailer.
+    extensions: Vec<ExtensionField>,
+    // addr is the address the server was queried at.
+    addr: SocketAddr,
+    // raw holds t1 to t4 as NTP timestamps, as they are (or would be) on the wire.
+    raw: [NTPTimestamp; 4],
+    // response is the header the server sent back.
+    response: NTPMessage,
+    // local_port is the UDP port the request was sent from; None over TLS.
+    local_port: Option<u16>,
+    // warnings are problems that did not stop the exchange, e.g. a failure to save it.
+    warnings: Vec<String>,
+}
+
+/// What a single server contributed to a check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerReport {
+    pub host: String,
+    // Offset from the local clock in milliseconds, if the server gave a usable response
+    pub offset: Option<i64>,
+    // Round-trip delay in milliseconds, if the server gave a usable response
+    pub delay: Option<i64>,
+    // Why the server's response was not used
+    pub error: Option<String>,
+    // The query timed out: the server did not answer in time, or resolving or connecting to it
+    // took too long
+    #[serde(default)]
+    pub timed_out: bool,
+    // NTP version the server was queried with
+    #[serde(default)]
+    pub version: u8,
+    // The result is less precise than usual, because of TLS overhead or an SNTP fallback
+    #[serde(default)]
+    pub low_confidence: bool,
+    // Bytes after the 48-byte header of the response, e.g. a key id and MAC; 0 if none
+    #[serde(default)]
+    pub trailer_len: usize,
+    // Extension fields found after the header of the response
+    #[serde(default)]
+    pub extension_fields: Vec<ExtensionField>,
+    // t1 to t4 of the used response as NTP timestamps, before any conversion
+    #[serde(default)]
+    pub timestamps: Option<[NTPTimestamp; 4]>,
+    // The server's answer, if it gave one, including one excluded as unsynchronized
+    #[serde(skip)]
+    pub exchange: Option<NTPResult>,
+}
+
+/// The outcome of querying every server and combining their offsets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckSummary {
+    // Weighted mean offset from the local clock in milliseconds
+    pub offset: f64,
+    // Servers that were excluded because their clock is not synchronized
+    pub unsynced: Vec<String>,
+    // Number of servers whose response was used
+    pub responses: usize,
+    // Mean round-trip delay of the used responses in milliseconds
+    pub delay: f64,
+    // Per-server results, in the order the servers were queried
+    pub servers: Vec<ServerReport>,
+    // What the query did beyond the per-server results, e.g. servers skipped or outliers
+    // discarded
+    #[serde(default)]
+    pub notes: Vec<String>,
+    // Problems that did not stop the query, e.g. a server answering implausibly fast
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// The error of a query that failed as a whole, carried inside the returned
+/// [`std::io::Error`] so that the per-server results are not lost. Get it back with
+/// [`QueryFailure::of`].
+#[derive(Debug, Clone)]
+pub struct QueryFailure {
+    pub message: String,
+    // Per-server results, in the order the servers were queried
+    pub servers: Vec<ServerReport>,
+    // As in CheckSummary
+    pub notes: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl QueryFailure {
+    /// The failure inside `err`, if `err` was returned by a query that got as far as querying
+    /// the servers.
+    pub fn of(err: &std::io::Error) -> Option<&QueryFailure> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    fn into_error(self, kind: ErrorKind) -> std::io::Error {
+        std::io::Error::new(kind, self)
+    }
+}
+
+impl std::fmt::Display for QueryFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for QueryFailure {}
+
+/// Every server's offset relative to a reference server instead of the local clock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceReport {
+    pub reference: String,
+    // The reference's own offset from the local clock in milliseconds
+    pub reference_offset: i64,
+    // The other servers, in the order they were queried
+    pub servers: Vec<RelativeOffset>,
+}
+
+/// One server's offset relative to the reference.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelativeOffset {
+    pub host: String,
+    // Server time minus reference time in milliseconds, if the server gave a usable response
+    pub offset: Option<i64>,
+    // Why the server's response was not used
+    pub error: Option<String>,
+}
+
+/// One server's IPv4 and IPv6 endpoints queried separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyComparison {
+    pub host: String,
+    // Why the server could not be resolved; both endpoints are then empty
+    pub error: Option<String>,
+    pub ipv4: FamilyResult,
+    pub ipv6: FamilyResult,
+}
+
+/// The result of querying a server at the first address of one family.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FamilyResult {
+    // The address queried; None if the server has no address in this family
+    pub addr: Option<IpAddr>,
+    // Offset from the local clock and round-trip delay in milliseconds, if usable
+    pub offset: Option<i64>,
+    pub delay: Option<i64>,
+    // Why the response was not used
+    pub error: Option<String>,
+}
+
+impl FamilyComparison {
+    /// The IPv6 offset minus the IPv4 offset and the tolerance implied by their delays, if both
+    /// endpoints answered. The two are consistent if the difference does not exceed the
+    /// tolerance; a larger gap points at a problem behind one endpoint.
+    pub fn difference(&self) -> Option<(i64, i64)> {
+        let (offset4, delay4) = (self.ipv4.offset?, self.ipv4.delay?);
+        let (offset6, delay6) = (self.ipv6.offset?, self.ipv6.delay?);
+        Some((offset6 - offset4, (delay4 + delay6) / 2))
+    }
 }
 
 impl NTPResult {
+    fn from_response(
+        response: &NTPMessage,
+        t1: DateTime<Utc>,
+        t4: DateTime<Utc>,
+        addr: SocketAddr,
+        client: &NtpClient,
+    ) -> Result<Self, std::io::Error> {
+        let raw = [
+            NTPTimestamp::try_from(t1)?,
+            response.rx_time()?,
+            response.tx_time()?,
+            NTPTimestamp::try_from(t4)?,
+        ];
+        let t2: DateTime<Utc> = raw[1].into();
+        let t3: DateTime<Utc> = raw[2].into();
+
+        Ok(NTPResult {
+            t1,
+            t2,
+            t3,
+            t4,
+            leap_indicator: response.leap_indicator(),
+            stratum: response.stratum(),
+            version: 0,
+            asymmetry: chrono::Duration::milliseconds(client.asymmetry),
+            low_confidence: None,
+            trailer_len: 0,
+            extensions: Vec::new(),
+            addr,
+            raw,
+            response: response.clone(),
+            local_port: None,
+            warnings: Vec::new(),
+        })
+    }
+
     pub fn delay(&self) -> i64 {
         // δ = (t4 - t1) - (t3 - t2)
         ((self.t4 - self.t1) - (self.t3 - self.t2)).num_milliseconds()
     }
 
     pub fn offset(&self) -> i64 {
-        // θ = ((t2 – t1) + (t4 – t3)) / 2
-        (((self.t2 - self.t1) + (self.t4 - self.t3)) / 2).num_milliseconds()
+        // θ = ((t2 – t1) + (t3 – t4) - asymmetry) / 2
+        // The classic formula assumes both directions take equally long; a known difference
+        // between the outbound and return one-way delays shifts the estimate by half of it.
+        (((self.t2 - self.t1) + (self.t3 - self.t4) - self.asymmetry) / 2).num_milliseconds()
+    }
+
+    /// A delay this low is only plausible on the local host: a remote "server" answering this
+    /// fast is likely a local cache or a misconfigured forward, not the intended upstream.
+    pub fn is_implausibly_fast(&self) -> bool {
+        // Below one millisecond, i.e. a delay that rounds to zero
+        const MIN_PLAUSIBLE_DELAY_MS: i64 = 1;
+        self.delay() < MIN_PLAUSIBLE_DELAY_MS && !self.addr.ip().is_loopback()
+    }
+
+    pub fn is_unsynchronized(&self) -> bool {
+        // LI = 11 is the alarm condition: the server's clock is not synchronized
+        self.leap_indicator == 0b11
+    }
+
+    pub fn stratum(&self) -> u8 {
+        self.stratum
+    }
+
+    pub fn leap_indicator(&self) -> u8 {
+        self.leap_indicator
+    }
+
+    /// The NTP version the request was sent with.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The address the server was queried at.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// t1 to t4 as NTP timestamps: request sent, received by the server, response sent by the
+    /// server, response received.
+    pub fn timestamps(&self) -> [NTPTimestamp; 4] {
+        self.raw
+    }
+
+    /// The header of the server's response.
+    pub fn response(&self) -> &NTPMessage {
+        &self.response
+    }
+
+    /// t1 to t4 as used for the offset and delay (see [`timestamps`](Self::timestamps)).
+    pub fn times(&self) -> [DateTime<Utc>; 4] {
+        [self.t1, self.t2, self.t3, self.t4]
+    }
+
+    /// Why the result is less precise than usual (TLS overhead or an SNTP fallback), if it is.
+    pub fn low_confidence(&self) -> Option<&'static str> {
+        self.low_confidence
+    }
+
+    /// The UDP port the request was sent from; None over TLS.
+    pub fn local_port(&self) -> Option<u16> {
+        self.local_port
+    }
+
+    /// Problems that did not stop the exchange, e.g. a failure to save the response.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// The number of bytes that followed the 48-byte header of the response, e.g. extension
+    /// fields or a key id and MAC; 0 if there were none.
+    pub fn trailer_len(&self) -> usize {
+        self.trailer_len
+    }
+
+    /// The extension fields found after the header of the response.
+    pub fn extension_fields(&self) -> &[ExtensionField] {
+        &self.extensions
     }
 }
 
 impl From<NTPTimestamp> for DateTime<Utc> {
     fn from(ntp: NTPTimestamp) -> Self {
         let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
-        let mut nanos = ntp.fraction as f64;
-        nanos *= 1e9;
-        nanos /= 2_f64.powi(32);
+        // fraction / 2^32 seconds, rounded down; even u32::MAX stays below 1e9 nanoseconds
+        let nanos = (ntp.fraction as u64 * NANOS_PER_SECOND) >> 32;
 
         Utc.timestamp_opt(secs, nanos as u32).unwrap()
     }
 }
 
-impl From<DateTime<Utc>> for NTPTimestamp {
-    fn from(utc: DateTime<Utc>) -> Self {
+impl TryFrom<DateTime<Utc>> for NTPTimestamp {
+    type Error = std::io::Error;
+
+    /// Fails for datetimes outside NTP era 0 (1900-01-01 to 2036-02-07T06:28:15Z), which the
+    /// 32-bit seconds field cannot represent.
+    fn try_from(utc: DateTime<Utc>) -> Result<Self, Self::Error> {
         let secs = utc.timestamp() + NTP_TO_UNIX_SECONDS;
-        let mut fraction = utc.nanosecond() as f64;
-        fraction *= 2_f64.powi(32);
-        fraction /= 1e9;
+        let seconds = u32::try_from(secs).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is outside the NTP era (1900-2036)", utc.to_rfc3339()),
+            )
+        })?;
+        // chrono reports a leap second as nanoseconds beyond 1e9; pin it to the end of the
+        // second so the fraction cannot overflow
+        let nanos = utc.nanosecond().min(999_999_999) as u64;
+        // Rounded up, so that converting back (which rounds down) gives the same nanoseconds
+        let fraction = (nanos << 32).div_ceil(NANOS_PER_SECOND);
 
-        NTPTimestamp {
-            seconds: secs as u32,
+        Ok(NTPTimestamp {
+            seconds,
             fraction: fraction as u32,
-        }
+        })
+    }
+}
+
+impl From<[u8; NTP_MESSAGE_LENGTH]> for NTPMessage {
+    fn from(data: [u8; NTP_MESSAGE_LENGTH]) -> Self {
+        NTPMessage { data }
+    }
+}
+
+impl Default for NTPMessage {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl NTPMessage {
-    fn new() -> Self {
+    pub fn new() -> Self {
         NTPMessage {
             data: [0; NTP_MESSAGE_LENGTH],
         }
     }
 
-    fn client() -> Self {
+    /// A client-mode (3) request with the given version number.
+    #[allow(clippy::unusual_byte_groupings)]
+    pub fn client(version: u8) -> Self {
         // 0 1 2 3 4 5 6 7 8
         // +-+-+-+-+-+-+-+-+
         // |LI | VN | MODE |
         const LEAP_INDICATOR: u8 = 0b_00_000_000;
-        const VERSION: u8 = 0b_00_011_000; // version 3 is NTP (version 4 is SNTP)
         const MODE: u8 = 0b_00_000_011; // client mode
 
         let mut message = Self::new();
-        message.data[0] |= LEAP_INDICATOR | VERSION | MODE;
+        message.data[0] |= LEAP_INDICATOR | MODE;
+        message.set_version(version);
         message
     }
 
+    pub fn leap_indicator(&self) -> u8 {
+        self.data[0] >> 6
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.data[0] >> 3) & 0b111
+    }
+
+    #[allow(clippy::unusual_byte_groupings)]
+    pub fn set_version(&mut self, version: u8) {
+        self.data[0] = (self.data[0] & 0b11_000_111) | ((version & 0b111) << 3);
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.data[0] & 0b111
+    }
+
+    pub fn stratum(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// The raw header, as sent or received.
+    pub fn as_bytes(&self) -> &[u8; NTP_MESSAGE_LENGTH] {
+        &self.data
+    }
+
     fn parse_timestamp(&self, i: usize) -> Result<NTPTimestamp, std::io::Error> {
         let mut reader = &self.data[i..i + 8];
         let seconds = reader.read_u32::<BigEndian>()?;
@@ -94,14 +612,14 @@ impl NTPMessage {
         Ok(NTPTimestamp { seconds, fraction })
     }
 
-    fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
+    pub fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
         // t2
-        self.parse_timestamp(32)
+        self.parse_timestamp(RX_TIME_OFFSET)
     }
 
-    fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
+    pub fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
         // t3
-        self.parse_timestamp(40)
+        self.parse_timestamp(TX_TIME_OFFSET)
     }
 }
 
@@ -109,7 +627,7 @@ impl NTPMessage {
 /// Each value has a weight, and values with higher weights have more influence on the result.
 /// weights: A vector of weights, each corresponding to a value in the `values` vector.
 /// values: A vector of values to calculate the weighted mean from.
-fn weighted_mean(values: &Vec<f64>, weights: &Vec<f64>) -> f64 {
+fn weighted_mean(values: &[f64], weights: &[f64]) -> f64 {
     let weighted_sum: f64 = values
         .iter()
         .zip(weights.iter())
@@ -121,65 +639,1517 @@ fn weighted_mean(values: &Vec<f64>, weights: &Vec<f64>) -> f64 {
     weighted_sum / total_weight // Divide the weighted sum by the total of all weights
 }
 
-fn ntp_roundtrim(host: &str, port: u16) -> Result<NTPResult, std::io::Error> {
-    let dest = format!("{}:{}", host, port);
-    let timeout = Duration::from_secs(1);
+/// How servers' offsets are weighted when they are averaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Weighting {
+    /// `1e6 / delay²`: the closer the server, the more its offset counts.
+    #[default]
+    Delay,
+    /// `1e6 / delay² × 2^-(stratum - 1)`: additionally halves the weight for every stratum
+    /// below a primary server, so a nearby stratum-3 server needs a quarter of the squared
+    /// delay of a stratum-1 server to count as much. Stratum 0 (unspecified) counts as 16.
+    Combined,
+}
+
+/// Returns the weight of an offset measured with `delay` milliseconds of round-trip delay from
+/// a server at `stratum`. Delays are measured in whole milliseconds, so a delay below 1ms
+/// (typically a server on the local network) counts as 1ms rather than as infinitely close.
+fn weight(delay: f64, stratum: u8, weighting: Weighting) -> f64 {
+    let delay = delay.max(1.0);
+    let by_delay = 1_000_000.0 / (delay * delay);
+    match weighting {
+        Weighting::Delay => by_delay,
+        Weighting::Combined => {
+            let stratum = if stratum == 0 { 16 } else { stratum.min(16) };
+            by_delay * 2_f64.powi(1 - stratum as i32)
+        }
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Removes every value that lies more than `threshold` MADs (median absolute deviations) away
+/// from the median, with the median and MAD computed once over all values. It returns the
+/// indices of the surviving values. The MAD is floored at 1.0 as in
+/// [`strip_outliers_iterative`].
+fn strip_outliers(values: &[f64], threshold: f64) -> Vec<usize> {
+    let center = median(values);
+    let deviations: Vec<f64> = values.iter().map(|v| (v - center).abs()).collect();
+    let mad = median(&deviations).max(1.0);
+
+    (0..values.len())
+        .filter(|&i| deviations[i] <= threshold * mad)
+        .collect()
+}
+
+/// This function repeatedly removes the value that deviates most from the median, as long as
+/// it lies more than `threshold` MADs (median absolute deviations) away, recomputing the median
+/// and MAD after every removal. It returns the indices of the surviving values.
+/// The MAD is floored at 1.0 because offsets are measured in whole milliseconds, and the
+/// number of removals is bounded so that at least half of the values always survive.
+fn strip_outliers_iterative(values: &[f64], threshold: f64) -> Vec<usize> {
+    let mut kept: Vec<usize> = (0..values.len()).collect();
+    let max_removals = values.len() / 2;
+
+    for _ in 0..max_removals {
+        let remaining: Vec<f64> = kept.iter().map(|&i| values[i]).collect();
+        let center = median(&remaining);
+        let deviations: Vec<f64> = remaining.iter().map(|v| (v - center).abs()).collect();
+        let mad = median(&deviations).max(1.0);
+
+        let (worst, deviation) = deviations
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        if *deviation <= threshold * mad {
+            break;
+        }
+
+        kept.remove(worst);
+    }
+
+    kept
+}
+
+/// Refuses to combine offsets that span more than `max_spread` milliseconds: when the servers
+/// disagree that much, no average of them can be trusted.
+fn check_spread(hosts: &[&str], offsets: &[f64], max_spread: u64) -> Result<(), std::io::Error> {
+    let max = offsets.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let min = offsets.iter().copied().fold(f64::INFINITY, f64::min);
+    let spread = max - min;
+
+    if spread > max_spread as f64 {
+        let servers: Vec<String> = hosts
+            .iter()
+            .zip(offsets)
+            .map(|(host, offset)| format!("{} {:+}ms", host, offset))
+            .collect();
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "servers disagree by {:.3}s; cannot determine time ({})",
+                spread / 1000.0,
+                servers.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Resolves `host` to its addresses. The system resolver cannot be given a timeout, so with
+/// `client.resolve_timeout` set it runs on a separate thread that is abandoned (and left to
+/// finish on its own) if it does not answer in time.
+fn resolve(host: &str, port: u16, client: &NtpClient) -> Result<Vec<SocketAddr>, std::io::Error> {
+    resolve_addrs(host, port, client)
+        .map_err(|err| std::io::Error::new(err.kind(), ResolveError(err)))
+}
+
+fn resolve_addrs(
+    host: &str,
+    port: u16,
+    client: &NtpClient,
+) -> Result<Vec<SocketAddr>, std::io::Error> {
+    let Some(timeout) = client.resolve_timeout else {
+        return Ok((host, port).to_socket_addrs()?.collect());
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let name = host.to_string();
+    thread::spawn(move || {
+        let addrs = (name.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>());
+        // The receiver is gone if resolution timed out
+        let _ = sender.send(addrs);
+    });
+
+    receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!("DNS resolution timed out after {}ms", timeout.as_millis()),
+        ))
+    })
+}
+
+/// A failure to resolve a server's name, marked as such so that [`is_resolve_failure`] can
+/// tell it apart from errors of the exchange itself. It displays as the underlying error.
+#[derive(Debug)]
+struct ResolveError(std::io::Error);
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Returns true if the error came from resolving a server's name rather than querying it.
+fn is_resolve_failure(err: &std::io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<ResolveError>())
+}
+
+/// Returns true if the error means the local machine has no route to the network at all.
+fn is_network_unreachable(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown | ErrorKind::HostUnreachable
+    )
+}
+
+/// Binds the local UDP socket in the address family of `dest`. Without a port range the OS
+/// picks an ephemeral port, so that concurrent queries never collide; with one, a random port
+/// inside the range is tried first and the rest of the range is walked from there until a free
+/// port is found.
+fn bind_socket(
+    source_ports: Option<&RangeInclusive<u16>>,
+    dest: SocketAddr,
+) -> Result<UdpSocket, std::io::Error> {
+    let local_ip = if dest.is_ipv6() {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    let range = match source_ports {
+        Some(range) => range,
+        None => return UdpSocket::bind((local_ip, 0)),
+    };
+
+    let len = (*range.end() as u32) - (*range.start() as u32) + 1;
+    let start = (RandomState::new().build_hasher().finish() % len as u64) as u32;
+
+    for i in 0..len {
+        let port = *range.start() as u32 + (start + i) % len;
+        match UdpSocket::bind((local_ip, port as u16)) {
+            Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::AddrInUse,
+        format!("no free source port in {}-{}", range.start(), range.end()),
+    ))
+}
+
+/// Turns a read that ran into `client.timeout` into a TimedOut error that says so. Depending on
+/// the platform such a read fails with WouldBlock or TimedOut and a generic OS message; other
+/// errors, such as a refused connection, are returned unchanged.
+fn response_timeout(err: std::io::Error, client: &NtpClient) -> std::io::Error {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "response took too long (no answer within {}ms)",
+                client.timeout.as_millis()
+            ),
+        ),
+        _ => err,
+    }
+}
+
+/// Checks that a response came from the server that was queried. Only the address has to
+/// match unless `strict_port` is set: servers behind NAT or with unusual network stacks can
+/// legitimately reply from a port other than the one that was queried.
+fn validate_source(
+    expected: SocketAddr,
+    actual: SocketAddr,
+    strict_port: bool,
+) -> Result<(), std::io::Error> {
+    if actual.ip() != expected.ip() || (strict_port && actual.port() != expected.port()) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("response came from {} instead of {}", actual, expected),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the raw bytes of a response to `<dir>/<host>_<address>.ntp`, creating `dir` if
+/// needed. Characters that are not safe in file names (such as IPv6 colons) become `_`.
+fn save_response(
+    dir: &Path,
+    host: &str,
+    source: SocketAddr,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    let name: String = format!("{}_{}", host, source)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(name + ".ntp"), data)
+}
+
+/// Rejects a sample if the system clock was stepped (by another process, or by a suspend and
+/// resume) between t1 and t4, which shows up as the wall-clock and monotonic clock disagreeing
+/// about how long the exchange took. Such a sample's offset would be meaningless.
+fn check_no_clock_step(
+    wall: chrono::Duration,
+    monotonic: std::time::Duration,
+) -> Result<(), std::io::Error> {
+    // Far more than even the fastest slewing can account for over one exchange
+    const MAX_DISAGREEMENT_MS: i64 = 10;
+
+    let disagreement = wall.num_milliseconds() - monotonic.as_millis() as i64;
+    if disagreement.abs() > MAX_DISAGREEMENT_MS {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("clock step of {}ms detected during the query", disagreement),
+        ));
+    }
+    Ok(())
+}
+
+fn ntp_roundtrim(host: &str, client: &NtpClient) -> Result<NTPResult, std::io::Error> {
+    if client.tls {
+        return tls_roundtrim(host, client);
+    }
+
+    let addrs = resolve(host, client.port, client)?;
+    let dest = pick_address(&addrs)?;
+    match query_addr(host, dest, client) {
+        // On an IPv6-only network the preferred IPv4 address has no route
+        Err(err) if dest.is_ipv4() && is_network_unreachable(&err) => {
+            match addrs.iter().find(|addr| addr.is_ipv6()) {
+                Some(&fallback) => query_addr(host, fallback, client),
+                None => Err(err),
+            }
+        }
+        result => result,
+    }
+}
+
+/// Chooses the address of a host to query.
+fn pick_address(addrs: &[SocketAddr]) -> Result<SocketAddr, std::io::Error> {
+    // Prefer IPv4, which is what has always been queried for dual-stack hosts
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "host has no addresses"))
+}
+
+/// Returns true if `a` and `b` share the first `v4_prefix` (IPv4) or `v6_prefix` (IPv6) bits.
+/// Addresses of different families never do.
+fn same_subnet(a: IpAddr, b: IpAddr, v4_prefix: u8, v6_prefix: u8) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::MAX.checked_shl(32 - v4_prefix as u32).unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::MAX.checked_shl(128 - v6_prefix as u32).unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Resolves every server and keeps only the first one of each subnet, so that servers at the
+/// same site do not count as independent samples. Each kept server comes with the address it
+/// must be queried at, or None if it could not be resolved (the query then reports the error).
+/// Every skipped server is described in `notes`.
+fn dedup_by_subnet<'a>(
+    client: &'a NtpClient,
+    prefixes: (u8, u8),
+    notes: &mut Vec<String>,
+) -> Vec<(&'a String, Option<SocketAddr>)> {
+    let resolved = client
+        .servers
+        .iter()
+        .map(|server| (server, resolve(server, client.port, client).ok()))
+        .collect();
+    dedup_resolved(resolved, prefixes, notes)
+}
+
+/// The deduplication of [`dedup_by_subnet`] over already resolved servers. A server is queried
+/// at the first of its addresses (IPv4 first, as with [`pick_address`]) that is not in the
+/// subnet of one already kept, and only skipped when all of them are.
+fn dedup_resolved<'a>(
+    resolved: Vec<(&'a String, Option<Vec<SocketAddr>>)>,
+    (v4_prefix, v6_prefix): (u8, u8),
+    notes: &mut Vec<String>,
+) -> Vec<(&'a String, Option<SocketAddr>)> {
+    let mut kept: Vec<(&String, Option<SocketAddr>)> = Vec::with_capacity(resolved.len());
 
-    let request = NTPMessage::client();
+    for (server, addrs) in resolved {
+        let Some(mut addrs) = addrs.filter(|addrs| !addrs.is_empty()) else {
+            kept.push((server, None));
+            continue;
+        };
+        addrs.sort_by_key(|addr| !addr.is_ipv4());
+
+        let duplicate_of = |addr: &SocketAddr| {
+            kept.iter().find_map(|(other, other_addr)| {
+                other_addr
+                    .filter(|o| same_subnet(o.ip(), addr.ip(), v4_prefix, v6_prefix))
+                    .map(|o| (*other, o))
+            })
+        };
+        if let Some(addr) = addrs.iter().find(|addr| duplicate_of(addr).is_none()) {
+            kept.push((server, Some(*addr)));
+            continue;
+        }
+        let (other, other_addr) = duplicate_of(&addrs[0]).expect("every address is a duplicate");
+        let ips: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+        notes.push(format!(
+            "skipping {} ({}), in the same /{} as {} ({})",
+            server,
+            ips.join(", "),
+            if addrs[0].is_ipv4() {
+                v4_prefix
+            } else {
+                v6_prefix
+            },
+            other,
+            other_addr.ip()
+        ));
+    }
+    kept
+}
+
+/// Performs one UDP exchange with `host` at the already resolved address `dest`.
+fn query_addr(
+    host: &str,
+    dest: SocketAddr,
+    client: &NtpClient,
+) -> Result<NTPResult, std::io::Error> {
+    let mut request = NTPMessage::client(client.version_for(host));
     let mut response = NTPMessage::new();
 
-    let udp = UdpSocket::bind(LOCAL_ADDR)?;
-    udp.connect(dest).expect("Unable to connect");
+    // By default the socket is deliberately left unconnected: a connected socket would make the
+    // kernel drop replies from any port but the queried one, before validate_source sees them.
+    // The price is that an ICMP port unreachable is never reported, so a closed port looks like
+    // a timeout. With strict_source_port such replies are rejected anyway, so the socket is
+    // connected and a refused query is reported as such.
+    let udp = bind_socket(client.source_ports.as_ref(), dest)?;
+    if client.strict_source_port {
+        udp.connect(dest)?;
+    }
+
+    // Padding only lengthens the datagram; the 48-byte header is unchanged and the response
+    // is still parsed from its first 48 bytes.
+    let mut payload = request.data.to_vec();
+    payload.resize(NTP_MESSAGE_LENGTH + client.pad as usize, 0);
+    if let Some(field) = &client.extension_field {
+        // Extension fields only exist in NTPv4
+        request.set_version(4);
+        payload[..NTP_MESSAGE_LENGTH].copy_from_slice(&request.data);
+        payload.extend_from_slice(&field.encode());
+    }
 
     let t1 = Utc::now();
-    udp.send(&request.data)?;
-    udp.set_read_timeout(Some(timeout))?;
-    udp.recv_from(&mut response.data)?;
+    let sent = Instant::now();
+    if client.strict_source_port {
+        udp.send(&payload)?;
+    } else {
+        udp.send_to(&payload, dest)?;
+    }
+    udp.set_read_timeout(Some(client.timeout))?;
+    let mut buffer = [0; RECV_BUFFER_LENGTH];
+    let (len, source) = udp.recv_from(&mut buffer).map_err(|err| match err.kind() {
+        // Windows reports the ICMP port unreachable as a reset connection
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => std::io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "query refused (nothing is listening on port {})",
+                dest.port()
+            ),
+        ),
+        _ => response_timeout(err, client),
+    })?;
     let t4 = Utc::now();
+    check_no_clock_step(t4 - t1, sent.elapsed())?;
+
+    validate_source(dest, source, client.strict_source_port)?;
+
+    let mut warnings = Vec::new();
+    if let Some(dir) = &client.save_response {
+        if let Err(err) = save_response(dir, host, source, &buffer[..len]) {
+            warnings.push(format!("Unable to save the response of {}: {}", host, err));
+        }
+    }
+
+    if len < NTP_MESSAGE_LENGTH {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("response is only {} bytes long", len),
+        ));
+    }
+    response.data.copy_from_slice(&buffer[..NTP_MESSAGE_LENGTH]);
+
+    let mut result = NTPResult::from_response(&response, t1, t4, dest, client)?;
+    result.version = request.version();
+    result.local_port = udp.local_addr().ok().map(|addr| addr.port());
+    check_response(&response, &mut result, client)?;
+    result.trailer_len = len - NTP_MESSAGE_LENGTH;
+    result.extensions = ExtensionField::decode_all(&buffer[NTP_MESSAGE_LENGTH..len]);
+    result.warnings = warnings;
+    Ok(result)
+}
 
-    let t2: DateTime<Utc> = response.rx_time().unwrap().into();
-    let t3: DateTime<Utc> = response.tx_time().unwrap().into();
+/// Performs the same exchange as `ntp_roundtrim`, but sends the NTP message over a TLS
+/// connection. The handshake is completed before t1 is taken; the result is low-confidence.
+fn tls_roundtrim(host: &str, client: &NtpClient) -> Result<NTPResult, std::io::Error> {
+    let request = NTPMessage::client(client.version_for(host));
+    let mut response = NTPMessage::new();
+
+    let addr = resolve(host, tls::NTP_TLS_PORT, client)?
+        .first()
+        .copied()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "host has no addresses"))?;
+    let mut stream = tls::connect(host, addr, client.timeout)?;
+
+    let t1 = Utc::now();
+    let sent = Instant::now();
+    stream.write_all(&request.data)?;
+    stream.flush()?;
+    stream
+        .read_exact(&mut response.data)
+        .map_err(|err| response_timeout(err, client))?;
+    let t4 = Utc::now();
+    check_no_clock_step(t4 - t1, sent.elapsed())?;
+
+    let mut result = NTPResult::from_response(&response, t1, t4, addr, client)?;
+    result.version = request.version();
+    check_response(&response, &mut result, client)?;
+    result.low_confidence = Some("TLS");
+    Ok(result)
+}
+
+/// Rejects responses that are not a server's answer with a usable time: the mode must be 4
+/// (server), the stratum 1 to 15 and the transmit timestamp set. Stratum 0 is a kiss-o'-death
+/// message, whose reference id carries a code such as RATE or DENY instead. A server that
+/// reports an unsynchronized clock (LI = 3) may use stratum 16 or more; it is kept so that it
+/// can be reported as such, but never used.
+fn check_header(response: &NTPMessage) -> Result<(), std::io::Error> {
+    let invalid = |message: String| Err(std::io::Error::new(ErrorKind::InvalidData, message));
+
+    if response.mode() != 4 {
+        return invalid(format!(
+            "unexpected mode {} (expected 4, server)",
+            response.mode()
+        ));
+    }
+    match response.stratum() {
+        0 => {
+            let code = &response.data[REF_ID_OFFSET..REF_ID_OFFSET + 4];
+            let code: String = code
+                .iter()
+                .take_while(|&&b| b != 0)
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '?' })
+                .collect();
+            return invalid(format!("kiss-o'-death from server (code {})", code));
+        }
+        1..=15 => (),
+        _ if response.leap_indicator() == 0b11 => (),
+        stratum => return invalid(format!("invalid stratum {} (expected 1 to 15)", stratum)),
+    }
+    if response.tx_time()?.seconds == 0 {
+        return invalid("transmit timestamp is not set".to_string());
+    }
+    Ok(())
+}
+
+/// Rejects invalid responses (see `check_header`) and responses that lack fields every full
+/// NTP server fills in: the reference id and reference timestamp. With `client.sntp_fallback`,
+/// such a response is still used as a simple SNTP estimate: the reference fields are not
+/// required, and a missing (zero) or later-than-transmit receive timestamp is taken to equal
+/// the transmit timestamp. The result is then marked as low-confidence.
+fn check_response(
+    response: &NTPMessage,
+    result: &mut NTPResult,
+    client: &NtpClient,
+) -> Result<(), std::io::Error> {
+    check_header(response)?;
+    // An unsynchronized server has no reference to report; it is excluded as unsynchronized
+    // later instead of being rejected here
+    if response.leap_indicator() == 0b11 {
+        return Ok(());
+    }
+
+    let problem = if response.data[REF_ID_OFFSET..REF_ID_OFFSET + 4] == [0; 4] {
+        "reference id is not set"
+    } else if response.parse_timestamp(REF_TIME_OFFSET)?.seconds == 0 {
+        "reference timestamp is not set"
+    } else {
+        return Ok(());
+    };
+
+    if !client.sntp_fallback {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, problem));
+    }
+    if response.rx_time()?.seconds == 0 || result.t2 > result.t3 {
+        result.t2 = result.t3;
+    }
+    result.low_confidence = Some("SNTP fallback");
+    Ok(())
+}
+
+/// Queries `host` `client.samples` times and keeps the response with the lowest delay, which
+/// is the one least disturbed by queuing on the network. A server is only used if at least
+/// `client.min_valid_samples` of its samples succeeded. With `dest`, the host is queried at that
+/// address instead of being resolved again.
+pub(crate) fn sample_server(
+    host: &str,
+    dest: Option<SocketAddr>,
+    client: &NtpClient,
+) -> Result<NTPResult, std::io::Error> {
+    let mut best: Option<NTPResult> = None;
+    let mut valid = 0;
+    let mut last_err = None;
+
+    for _ in 0..client.samples {
+        let sample = match dest {
+            Some(dest) if !client.tls => query_addr(host, dest, client),
+            _ => ntp_roundtrim(host, client),
+        };
+        match sample {
+            Ok(time) => {
+                valid += 1;
+                if best.as_ref().is_none_or(|b| time.delay() < b.delay()) {
+                    best = Some(time);
+                }
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
 
-    Ok(NTPResult { t1, t2, t3, t4 })
+    match (best, last_err) {
+        (None, Some(err)) => Err(err),
+        (Some(_), _) if valid < client.min_valid_samples => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "only {} of {} samples valid, {} required",
+                valid, client.samples, client.min_valid_samples
+            ),
+        )),
+        (best, _) => Ok(best.expect("samples is at least 1")),
+    }
 }
 
-pub fn check_time() -> Result<f64, std::io::Error> {
-    const NTP_PORT: u16 = 123;
-    let servers = [
-        "time.nist.gov",
-        "time.apple.com",
-        "time.euro.apple.com",
-        "time.google.com",
-        "time2.google.com",
-    ];
+pub(crate) fn check_time(client: &NtpClient) -> Result<CheckSummary, std::io::Error> {
+    // Number of MADs a value may deviate from the median before it is treated as an outlier
+    const ROBUST_THRESHOLD: f64 = 3.0;
+    if client.servers.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "no NTP servers configured",
+        ));
+    }
+    let mut notes = Vec::new();
+    let mut warnings = Vec::new();
+    let servers = match client.dedup_subnet {
+        Some(prefixes) => dedup_by_subnet(client, prefixes, &mut notes),
+        None => client.servers.iter().map(|server| (server, None)).collect(),
+    };
+    // Every server is queried on its own thread, so that slow or unreachable servers cost one
+    // timeout in total rather than one each. Results are collected in the configured order,
+    // which keeps the per-server reports below stable. A source port range with fewer ports
+    // than servers could not serve them all at once, so they are then queried one after
+    // another.
+    let parallel = client
+        .source_ports
+        .as_ref()
+        .is_none_or(|range| range.len() >= servers.len());
+    let results: Vec<_> = if parallel {
+        std::thread::scope(|scope| {
+            let queries: Vec<_> = servers
+                .into_iter()
+                .map(|(server, dest)| {
+                    (
+                        server,
+                        scope.spawn(move || sample_server(server, dest, client)),
+                    )
+                })
+                .collect();
+            queries
+                .into_iter()
+                .map(|(server, query)| {
+                    let result = query
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                    (server, result)
+                })
+                .collect()
+        })
+    } else {
+        servers
+            .into_iter()
+            .map(|(server, dest)| (server, sample_server(server, dest, client)))
+            .collect()
+    };
+
+    // Counted before any of the checks below can fail the query as a whole
+    if let Some(metrics) = &client.metrics {
+        metrics.record_servers(results.iter().map(|(server, result)| {
+            let used = result.as_ref().is_ok_and(|time| !time.is_unsynchronized());
+            (server.as_str(), used)
+        }));
+    }
 
-    let mut times = Vec::with_capacity(servers.len());
+    // When every server fails because there is no route at all, or because not even its name
+    // could be resolved (which is where an offline machine fails first for hostnames), the
+    // problem is the local network rather than the servers.
+    let offline = results.iter().all(|(_, result)| match result {
+        Ok(_) => false,
+        Err(err) => is_network_unreachable(err) || is_resolve_failure(err),
+    });
 
-    for server in servers {
-        print!("{} => ", server);
+    let mut times = Vec::with_capacity(results.len());
+    let mut unsynced = Vec::new();
+    let mut reports = Vec::with_capacity(results.len());
 
-        match ntp_roundtrim(server, NTP_PORT) {
+    for (server, result) in results {
+        let mut report = ServerReport {
+            host: server.to_string(),
+            offset: None,
+            delay: None,
+            error: None,
+            timed_out: false,
+            version: client.version_for(server),
+            low_confidence: false,
+            trailer_len: 0,
+            extension_fields: Vec::new(),
+            timestamps: None,
+            exchange: None,
+        };
+        match result {
+            Ok(time) if time.is_unsynchronized() => {
+                unsynced.push(server.to_string());
+                report.error = Some("clock not synchronized".to_string());
+                report.version = time.version;
+                report.exchange = Some(time);
+            }
             Ok(time) => {
-                println!("{}ms away from local system time", time.offset());
-                times.push(time);
+                if time.is_implausibly_fast() {
+                    warnings.push(format!(
+                        "{} ({}) answered in {}ms; it may be a local cache rather than an upstream server",
+                        server,
+                        time.addr.ip(),
+                        time.delay()
+                    ));
+                }
+                warnings.extend(time.warnings.iter().cloned());
+                report.offset = Some(time.offset());
+                report.delay = Some(time.delay());
+                report.low_confidence = time.low_confidence.is_some();
+                report.trailer_len = time.trailer_len;
+                report.version = time.version;
+                report.extension_fields = time.extensions.clone();
+                report.timestamps = Some(time.raw);
+                report.exchange = Some(time.clone());
+                times.push((server.as_str(), time));
+            }
+            Err(err) => {
+                report.error = Some(err.to_string());
+                report.timed_out = err.kind() == ErrorKind::TimedOut;
             }
-            Err(_) => println!("? [response took too long]"),
         }
+        reports.push(report);
+    }
+
+    let failure = |message: String, notes: Vec<String>, warnings: Vec<String>| QueryFailure {
+        message,
+        servers: reports.clone(),
+        notes,
+        warnings,
+    };
+    if offline {
+        return Err(failure(
+            "no network connectivity (no NTP server could be resolved or reached)".to_string(),
+            notes,
+            warnings,
+        )
+        .into_error(ErrorKind::NetworkUnreachable));
     }
+    if times.is_empty() {
+        let message = if unsynced.is_empty() {
+            "no NTP servers reachable (no server gave a usable response)".to_string()
+        } else {
+            format!(
+                "no server gave a usable response ({} unsynchronized)",
+                unsynced.len()
+            )
+        };
+        return Err(failure(message, notes, warnings).into_error(ErrorKind::Other));
+    }
+
+    let mut hosts = Vec::with_capacity(times.len());
     let mut offsets = Vec::with_capacity(times.len());
     let mut offset_weights = Vec::with_capacity(times.len());
 
-    for time in &times {
+    for (host, time) in &times {
         let offset = time.offset() as f64;
         let delay = time.delay() as f64;
 
-        let weight = 1_000_000.0 / (delay * delay);
-        if weight.is_finite() {
-            offsets.push(offset);
-            offset_weights.push(weight);
+        hosts.push(*host);
+        offsets.push(offset);
+        offset_weights.push(weight(delay, time.stratum, client.weighting));
+    }
+
+    let filter = match (client.max_deviation, client.robust) {
+        (Some(k), false) => Some((k, strip_outliers(&offsets, k))),
+        (k, true) => {
+            let k = k.unwrap_or(ROBUST_THRESHOLD);
+            Some((k, strip_outliers_iterative(&offsets, k)))
+        }
+        (None, false) => None,
+    };
+    if let Some((threshold, kept)) = filter {
+        let outliers: Vec<String> = (0..hosts.len())
+            .filter(|i| !kept.contains(i))
+            .map(|i| format!("{} ({}ms)", hosts[i], offsets[i]))
+            .collect();
+        if !outliers.is_empty() {
+            notes.push(format!(
+                "discarded outlying offset(s) beyond {} MADs: {}",
+                threshold,
+                outliers.join(", ")
+            ));
+        }
+        hosts = kept.iter().map(|&i| hosts[i]).collect();
+        offsets = kept.iter().map(|&i| offsets[i]).collect();
+        offset_weights = kept.iter().map(|&i| offset_weights[i]).collect();
+    }
+
+    if let Some(max_spread) = client.max_spread {
+        if let Err(err) = check_spread(&hosts, &offsets, max_spread) {
+            return Err(failure(err.to_string(), notes, warnings).into_error(err.kind()));
         }
     }
 
     let avg_offset = weighted_mean(&offsets, &offset_weights);
-    Ok(avg_offset)
+    let delay = times
+        .iter()
+        .map(|(_, time)| time.delay() as f64)
+        .sum::<f64>()
+        / times.len() as f64;
+    Ok(CheckSummary {
+        offset: avg_offset,
+        unsynced,
+        responses: times.len(),
+        delay,
+        servers: reports,
+        notes,
+        warnings,
+    })
+}
+
+/// Queries every server and reports its offset relative to the `reference` server's time
+/// instead of the local clock. Fails only if the reference itself gives no usable answer.
+pub(crate) fn check_against_reference(
+    client: &NtpClient,
+    reference: &str,
+) -> Result<ReferenceReport, std::io::Error> {
+    let base = ntp_roundtrim(reference, client)?;
+    if base.is_unsynchronized() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "reference clock is not synchronized",
+        ));
+    }
+    let base = base.offset();
+
+    let servers = client
+        .servers
+        .iter()
+        .filter(|server| *server != reference)
+        .map(|server| {
+            let (offset, error) = match ntp_roundtrim(server, client) {
+                Ok(time) if time.is_unsynchronized() => {
+                    (None, Some("clock not synchronized".to_string()))
+                }
+                // (server - local) - (reference - local) = server - reference
+                Ok(time) => (Some(time.offset() - base), None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            RelativeOffset {
+                host: server.clone(),
+                offset,
+                error,
+            }
+        })
+        .collect();
+    Ok(ReferenceReport {
+        reference: reference.to_string(),
+        reference_offset: base,
+        servers,
+    })
+}
+
+/// Queries the first IPv4 and the first IPv6 address of every server separately, so that
+/// their offsets can be compared (see [`FamilyComparison::difference`]).
+pub(crate) fn compare_families(client: &NtpClient) -> Vec<FamilyComparison> {
+    let mut comparisons = Vec::with_capacity(client.servers.len());
+    for server in &client.servers {
+        let mut comparison = FamilyComparison {
+            host: server.clone(),
+            error: None,
+            ipv4: FamilyResult::default(),
+            ipv6: FamilyResult::default(),
+        };
+        let addrs = match resolve(server, client.port, client) {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                comparison.error = Some(err.to_string());
+                comparisons.push(comparison);
+                continue;
+            }
+        };
+
+        for (family, is_family) in [
+            (
+                &mut comparison.ipv4,
+                SocketAddr::is_ipv4 as fn(&SocketAddr) -> bool,
+            ),
+            (&mut comparison.ipv6, SocketAddr::is_ipv6),
+        ] {
+            let Some(&dest) = addrs.iter().find(|addr| is_family(addr)) else {
+                continue;
+            };
+            family.addr = Some(dest.ip());
+            match query_addr(server, dest, client) {
+                Ok(time) if time.is_unsynchronized() => {
+                    family.error = Some("clock not synchronized".to_string())
+                }
+                Ok(time) => {
+                    family.offset = Some(time.offset());
+                    family.delay = Some(time.delay());
+                }
+                Err(err) => family.error = Some(err.to_string()),
+            }
+        }
+        comparisons.push(comparison);
+    }
+    comparisons
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::NtpMetrics;
+
+    /// How the test responder answers a request.
+    #[derive(Debug, Clone)]
+    struct Reply {
+        leap_indicator: u8,
+        stratum: u8,
+        // Added to the responder's clock for the receive and transmit timestamps
+        skew: chrono::Duration,
+        // Time between receiving the request and sending the response
+        delay: Duration,
+        // Reference id; all zeros leaves it unset
+        ref_id: [u8; 4],
+        // Reference timestamp; None leaves it unset
+        ref_time: Option<NTPTimestamp>,
+        // Bytes sent after the header, e.g. extension fields or a MAC
+        trailer: Vec<u8>,
+        // Answer from a second socket, i.e. a different source port than the one queried
+        other_port: bool,
+    }
+
+    impl Default for Reply {
+        fn default() -> Self {
+            Reply {
+                leap_indicator: 0,
+                stratum: 2,
+                skew: chrono::Duration::zero(),
+                delay: Duration::ZERO,
+                ref_id: *b"TEST",
+                ref_time: Some(NTPTimestamp {
+                    seconds: 3_900_000_000,
+                    fraction: 0,
+                }),
+                trailer: Vec::new(),
+                other_port: false,
+            }
+        }
+    }
+
+    impl Reply {
+        /// The server-mode header answering `request`, stamped with `rx` and `tx`.
+        fn header(&self, request: &[u8], rx: NTPTimestamp, tx: NTPTimestamp) -> NTPMessage {
+            let mut data = [0; NTP_MESSAGE_LENGTH];
+            data[0] = (self.leap_indicator << 6) | (request[0] & 0b0011_1000) | 4;
+            data[1] = self.stratum;
+            data[REF_ID_OFFSET..REF_ID_OFFSET + 4].copy_from_slice(&self.ref_id);
+            let ref_time = self.ref_time.unwrap_or_default();
+            let stamps = [
+                (REF_TIME_OFFSET, ref_time),
+                (RX_TIME_OFFSET, rx),
+                (TX_TIME_OFFSET, tx),
+            ];
+            for (offset, stamp) in stamps {
+                data[offset..offset + 4].copy_from_slice(&stamp.seconds.to_be_bytes());
+                data[offset + 4..offset + 8].copy_from_slice(&stamp.fraction.to_be_bytes());
+            }
+            // Origin timestamp: the request's transmit timestamp
+            data[24..32].copy_from_slice(&request[TX_TIME_OFFSET..TX_TIME_OFFSET + 8]);
+            NTPMessage::from(data)
+        }
+    }
+
+    /// Starts an NTP server on `ip`:`port` (0 picks a free port) that answers every request with
+    /// `reply` until the test process exits, and returns the port it listens on.
+    fn responder(ip: &str, port: u16, reply: Reply) -> u16 {
+        let socket = UdpSocket::bind((ip, port)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let out = if reply.other_port {
+            UdpSocket::bind((ip, 0)).unwrap()
+        } else {
+            socket.try_clone().unwrap()
+        };
+        thread::spawn(move || {
+            let mut request = [0; NTP_MESSAGE_LENGTH];
+            while let Ok((_, peer)) = socket.recv_from(&mut request) {
+                let rx = NTPTimestamp::try_from(Utc::now() + reply.skew).unwrap();
+                thread::sleep(reply.delay);
+                let tx = NTPTimestamp::try_from(Utc::now() + reply.skew).unwrap();
+                let mut response = reply.header(&request, rx, tx).as_bytes().to_vec();
+                response.extend_from_slice(&reply.trailer);
+                let _ = out.send_to(&response, peer);
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn measures_the_offset_of_a_responder() {
+        let reply = Reply {
+            skew: chrono::Duration::milliseconds(500),
+            ..Reply::default()
+        };
+        let port = responder("127.0.0.1", 0, reply);
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1"])
+            .port(port)
+            .build();
+
+        let summary = client.query().unwrap();
+        assert_eq!(summary.responses, 1);
+        assert!(
+            (summary.offset - 500.0).abs() < 50.0,
+            "offset {}",
+            summary.offset
+        );
+        assert_eq!(summary.servers[0].host, "127.0.0.1");
+        assert!(summary.servers[0].error.is_none());
+    }
+
+    #[test]
+    fn accepts_an_unsynchronized_server_without_a_reference() {
+        // What ntpd sends before it has synchronized
+        let reply = Reply {
+            leap_indicator: 0b11,
+            stratum: 16,
+            ref_id: *b"INIT",
+            ref_time: None,
+            ..Reply::default()
+        };
+        let now = NTPTimestamp::try_from(Utc::now()).unwrap();
+        let request = NTPMessage::client(4);
+        let response = reply.header(request.as_bytes(), now, now);
+        let client = NtpClient::builder().build();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 123));
+        let mut result =
+            NTPResult::from_response(&response, Utc::now(), Utc::now(), addr, &client).unwrap();
+
+        check_response(&response, &mut result, &client).unwrap();
+        assert!(result.is_unsynchronized());
+
+        let port = responder("127.0.0.1", 0, reply);
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1"])
+            .port(port)
+            .build();
+        let err = client.query().unwrap_err();
+        assert!(err.to_string().contains("unsynchronized"), "{err}");
+    }
+
+    #[test]
+    fn strip_outliers_drops_a_wildly_wrong_sample() {
+        let offsets = [12.0, 10.0, 11.0, 5000.0, 13.0, 9.0];
+        assert_eq!(strip_outliers(&offsets, 3.0), vec![0, 1, 2, 4, 5]);
+        // Tight samples all survive, even with a small threshold
+        assert_eq!(strip_outliers(&offsets[..3], 1.0), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn strip_outliers_iterative_finds_an_outlier_masked_by_another() {
+        // With 1000 present the MAD is 5, so 10 is within 3 MADs; without it the MAD is 2
+        let offsets = [0.0, 2.0, 10.0, 1000.0];
+        assert_eq!(strip_outliers(&offsets, 3.0), vec![0, 1, 2]);
+        assert_eq!(strip_outliers_iterative(&offsets, 3.0), vec![0, 1]);
+    }
+
+    #[test]
+    fn strip_outliers_iterative_keeps_at_least_half() {
+        let offsets = [0.0, 100.0, 200.0, 300.0, 400.0, 500.0, 600.0];
+        assert_eq!(strip_outliers_iterative(&offsets, 0.5).len(), 4);
+        assert_eq!(strip_outliers_iterative(&offsets[..6], 0.5).len(), 3);
+    }
+
+    #[test]
+    fn validate_source_checks_the_port_only_when_strict() {
+        let expected: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let other_port: SocketAddr = "192.0.2.1:4123".parse().unwrap();
+        let other_ip: SocketAddr = "192.0.2.2:123".parse().unwrap();
+
+        assert!(validate_source(expected, expected, true).is_ok());
+        assert!(validate_source(expected, other_port, false).is_ok());
+        let err = validate_source(expected, other_port, true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        for strict_port in [false, true] {
+            let err = validate_source(expected, other_ip, strict_port).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn ntp_timestamps_cover_era_0_only() {
+        let utc = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        let start = NTPTimestamp::try_from(utc("1900-01-01T00:00:00Z")).unwrap();
+        assert_eq!((start.seconds, start.fraction), (0, 0));
+        let end = NTPTimestamp::try_from(utc("2036-02-07T06:28:15Z")).unwrap();
+        assert_eq!((end.seconds, end.fraction), (u32::MAX, 0));
+
+        for outside in [
+            "1899-12-31T23:59:59Z",
+            "1850-06-01T12:00:00Z",
+            "2036-02-07T06:28:16Z",
+            "2100-01-01T00:00:00Z",
+        ] {
+            let err = NTPTimestamp::try_from(utc(outside)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{outside}");
+        }
+    }
+
+    #[test]
+    fn ntp_fractions_round_trip() {
+        for fraction in [0, 1, 0x8000_0000, 4_294_967_291, u32::MAX] {
+            let ntp = NTPTimestamp {
+                seconds: 3_900_000_000,
+                fraction,
+            };
+            let back = NTPTimestamp::try_from(DateTime::<Utc>::from(ntp)).unwrap();
+            assert_eq!(back.seconds, ntp.seconds);
+            // One nanosecond is about 4.3 fraction units
+            assert!(
+                fraction.abs_diff(back.fraction) <= 4,
+                "{fraction} -> {}",
+                back.fraction
+            );
+        }
+
+        let date = chrono::NaiveDate::from_ymd_opt(2016, 12, 31).unwrap();
+        for nanos in [0, 1, 500_000_000, 999_999_999] {
+            let utc = date.and_hms_nano_opt(12, 0, 0, nanos).unwrap().and_utc();
+            let back = DateTime::<Utc>::from(NTPTimestamp::try_from(utc).unwrap());
+            assert_eq!(back, utc);
+        }
+
+        // A leap second is pinned to the end of the second before it
+        let leap = date
+            .and_hms_nano_opt(23, 59, 59, 1_500_000_000)
+            .unwrap()
+            .and_utc();
+        let back = DateTime::<Utc>::from(NTPTimestamp::try_from(leap).unwrap());
+        assert_eq!(back.timestamp(), leap.timestamp());
+        assert_eq!(back.nanosecond(), 999_999_999);
+    }
+
+    #[test]
+    fn bind_socket_matches_the_address_family() {
+        let socket = bind_socket(None, "[::1]:123".parse().unwrap()).unwrap();
+        let local = socket.local_addr().unwrap();
+        assert!(local.is_ipv6());
+        assert_ne!(local.port(), 0);
+
+        let socket = bind_socket(None, "127.0.0.1:123".parse().unwrap()).unwrap();
+        let local = socket.local_addr().unwrap();
+        assert!(local.is_ipv4());
+        assert_ne!(local.port(), 0);
+    }
+
+    #[test]
+    fn check_no_clock_step_compares_wall_and_monotonic_time() {
+        let wall = chrono::Duration::milliseconds;
+        let monotonic = Duration::from_millis;
+
+        assert!(check_no_clock_step(wall(40), monotonic(40)).is_ok());
+        assert!(check_no_clock_step(wall(45), monotonic(40)).is_ok());
+        // Stepped forward, then backward, during the exchange
+        for (wall, monotonic) in [(wall(1040), monotonic(40)), (wall(-960), monotonic(40))] {
+            let err = check_no_clock_step(wall, monotonic).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        assert!(check_no_clock_step(wall(51), monotonic(40)).is_err());
+        assert!(check_no_clock_step(wall(29), monotonic(40)).is_err());
+    }
+
+    #[test]
+    fn weight_favors_low_strata_and_floors_the_delay() {
+        let combined = |delay, stratum| weight(delay, stratum, Weighting::Combined);
+
+        assert_eq!(combined(20.0, 1) / combined(20.0, 3), 4.0);
+        assert_eq!(combined(20.0, 0), combined(20.0, 16));
+        assert_eq!(
+            weight(0.0, 1, Weighting::Delay),
+            weight(1.0, 1, Weighting::Delay)
+        );
+        assert_eq!(combined(0.3, 2), combined(1.0, 2));
+        // Stratum plays no part when weighting by delay alone
+        assert_eq!(
+            weight(20.0, 1, Weighting::Delay),
+            weight(20.0, 3, Weighting::Delay)
+        );
+    }
+
+    #[test]
+    fn reports_servers_in_input_order() {
+        // The slowest responder is listed first, so replies arrive in a different order
+        let delays = [200, 0, 100];
+        let hosts = ["127.0.0.1", "127.0.0.2", "127.0.0.3"];
+        let mut port = 0;
+        for (host, delay) in hosts.iter().zip(delays) {
+            let reply = Reply {
+                delay: Duration::from_millis(delay),
+                ..Reply::default()
+            };
+            port = responder(host, port, reply);
+        }
+        let client = NtpClient::builder().servers(hosts).port(port).build();
+
+        let summary = client.query().unwrap();
+        assert_eq!(summary.responses, 3);
+        let reported: Vec<&str> = summary.servers.iter().map(|s| s.host.as_str()).collect();
+        assert_eq!(reported, hosts);
+    }
+
+    #[test]
+    fn counts_servers_of_failed_queries() {
+        let server_failures = |metrics: &NtpMetrics| -> Vec<(String, u64, u64)> {
+            let snapshot = metrics.snapshot();
+            assert_eq!((snapshot.polls, snapshot.failures), (1, 1));
+            snapshot
+                .servers
+                .into_iter()
+                .map(|s| (s.host, s.successes, s.failures))
+                .collect()
+        };
+
+        // Every server times out
+        let reply = Reply {
+            delay: Duration::from_millis(500),
+            ..Reply::default()
+        };
+        let port = responder("127.0.0.1", 0, reply);
+        let metrics = Arc::new(NtpMetrics::new());
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1"])
+            .port(port)
+            .timeout(Duration::from_millis(100))
+            .metrics(metrics.clone())
+            .build();
+        assert!(client.query().is_err());
+        assert_eq!(server_failures(&metrics), [("127.0.0.1".into(), 0, 1)]);
+
+        // The servers disagree by more than --max-spread
+        let port = responder("127.0.0.1", 0, Reply::default());
+        let reply = Reply {
+            skew: chrono::Duration::seconds(5),
+            ..Reply::default()
+        };
+        responder("127.0.0.2", port, reply);
+        let metrics = Arc::new(NtpMetrics::new());
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1", "127.0.0.2"])
+            .port(port)
+            .max_spread(Some(100))
+            .metrics(metrics.clone())
+            .build();
+        assert!(client.query().is_err());
+        assert_eq!(
+            server_failures(&metrics),
+            [("127.0.0.1".into(), 1, 0), ("127.0.0.2".into(), 1, 0)]
+        );
+    }
+
+    #[test]
+    fn exposes_the_trailer_of_a_response() {
+        let field = ExtensionField {
+            field_type: 0x0104,
+            value: vec![7; 12],
+        };
+        // A 16-byte extension field, then a key id and a 16-byte MAC
+        let mut trailer = vec![0x01, 0x04, 0, 16];
+        trailer.extend_from_slice(&field.value);
+        trailer.extend_from_slice(&[1; 20]);
+        let reply = Reply {
+            trailer,
+            ..Reply::default()
+        };
+        let port = responder("127.0.0.1", 0, reply);
+        let client = NtpClient::builder().port(port).build();
+
+        let result = client.query_server("127.0.0.1").unwrap();
+        assert_eq!(result.trailer_len(), 36);
+        assert_eq!(result.extension_fields(), [field]);
+    }
+
+    #[test]
+    fn rejects_an_empty_server_list() {
+        let client = NtpClient::builder().servers(Vec::<String>::new()).build();
+        let err = client.query().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reports_no_connectivity_when_no_server_resolves() {
+        // .invalid names never resolve, as for every name on a machine without network
+        let client = NtpClient::builder()
+            .servers(["ntp.invalid", "time.invalid"])
+            .build();
+        let err = client.query().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NetworkUnreachable, "{err}");
+
+        // One reachable server means the network is up
+        let port = responder("127.0.0.1", 0, Reply::default());
+        let client = NtpClient::builder()
+            .servers(["ntp.invalid", "localhost"])
+            .port(port)
+            .build();
+        let summary = client.query().unwrap();
+        assert_eq!(summary.responses, 1);
+        assert!(summary.servers[0].error.is_some());
+    }
+
+    #[test]
+    fn extension_fields_round_trip_without_a_mac() {
+        for len in [0, 1, 12, 13, 16, 17, 20, 21, 24, 25, 40] {
+            let field = ExtensionField {
+                field_type: 0x0104,
+                value: vec![0xab; len],
+            };
+            let data = field.encode();
+            assert!(data.len() >= 28 && data.len().is_multiple_of(4), "{len}");
+
+            let decoded = ExtensionField::decode_all(&data);
+            assert_eq!(decoded.len(), 1, "value of {len} bytes was read as a MAC");
+            assert_eq!(decoded[0].field_type, field.field_type);
+            // The value comes back with its padding
+            let (value, padding) = decoded[0].value.split_at(len);
+            assert_eq!(value, field.value);
+            assert!(padding.iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn returns_results_instead_of_printing_them() {
+        let port = responder("127.0.0.1", 0, Reply::default());
+        let reply = Reply {
+            skew: chrono::Duration::milliseconds(300),
+            ..Reply::default()
+        };
+        responder("127.0.0.2", port, reply);
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1", "127.0.0.2"])
+            .port(port)
+            .build();
+
+        let against = client.query_against("127.0.0.1").unwrap();
+        assert_eq!(against.servers.len(), 1);
+        assert_eq!(against.servers[0].host, "127.0.0.2");
+        let relative = against.servers[0].offset.unwrap();
+        assert!((relative - 300).abs() < 50, "relative offset {relative}");
+
+        let families = client.compare_families();
+        assert_eq!(families.len(), 2);
+        assert!(families[0].ipv4.offset.is_some());
+        assert_eq!(families[0].ipv6.addr, None);
+        assert_eq!(families[0].difference(), None);
+
+        // A failed query still carries what every server did
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1", "127.0.0.2"])
+            .port(port)
+            .max_spread(Some(100))
+            .build();
+        let err = client.query().unwrap_err();
+        let failure = QueryFailure::of(&err).unwrap();
+        assert_eq!(failure.message, err.to_string());
+        assert_eq!(failure.servers.len(), 2);
+        assert!(failure.servers.iter().all(|s| s.offset.is_some()));
+    }
+
+    #[test]
+    fn query_best_of_runs_at_least_one_round() {
+        let port = responder("127.0.0.1", 0, Reply::default());
+        let client = NtpClient::builder()
+            .servers(["127.0.0.1"])
+            .port(port)
+            .build();
+
+        let (round, summary) = client.query_best_of(0).unwrap();
+        assert_eq!(round, 1);
+        assert_eq!(summary.responses, 1);
+    }
+
+    #[test]
+    fn max_deviation_with_robust_sets_the_iterative_threshold() {
+        let hosts = ["127.0.0.1", "127.0.0.2", "127.0.0.3", "127.0.0.4"];
+        let mut port = 0;
+        for (host, skew) in hosts.iter().zip([0, 20, 100, 10_000]) {
+            let reply = Reply {
+                skew: chrono::Duration::milliseconds(skew),
+                ..Reply::default()
+            };
+            port = responder(host, port, reply);
+        }
+        let discarded = |robust: bool| {
+            let client = NtpClient::builder()
+                .servers(hosts)
+                .port(port)
+                .max_deviation(Some(3.0))
+                .robust(robust)
+                .build();
+            client.query().unwrap().notes.join("\n")
+        };
+
+        // A single pass only catches the sample that is wildly off
+        let single = discarded(false);
+        assert!(
+            single.contains("127.0.0.4") && !single.contains("127.0.0.3"),
+            "{single}"
+        );
+        let iterative = discarded(true);
+        assert!(
+            iterative.contains("127.0.0.4") && iterative.contains("127.0.0.3"),
+            "{iterative}"
+        );
+    }
+
+    #[test]
+    fn reports_a_closed_port_as_refused_in_strict_mode() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let query = |strict: bool| {
+            NtpClient::builder()
+                .port(port)
+                .timeout(Duration::from_millis(100))
+                .strict_source_port(strict)
+                .build()
+                .query_server("127.0.0.1")
+                .unwrap_err()
+        };
+
+        // Unconnected, the ICMP port unreachable never reaches the socket
+        assert_eq!(query(false).kind(), ErrorKind::TimedOut);
+        assert_eq!(query(true).kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn accepts_a_reply_from_another_port_unless_strict() {
+        let reply = Reply {
+            other_port: true,
+            ..Reply::default()
+        };
+        let port = responder("127.0.0.1", 0, reply);
+        let query = |strict: bool| {
+            NtpClient::builder()
+                .port(port)
+                .timeout(Duration::from_millis(200))
+                .strict_source_port(strict)
+                .build()
+                .query_server("127.0.0.1")
+        };
+
+        let result = query(false).unwrap();
+        assert_eq!(result.addr().port(), port);
+        // The connected socket never sees a reply from any other port
+        assert_eq!(query(true).unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn queries_an_ipv6_server() {
+        let port = responder("::1", 0, Reply::default());
+        let client = NtpClient::builder().port(port).build();
+
+        let result = client.query_server("::1").unwrap();
+        assert!(result.addr().is_ipv6(), "{}", result.addr());
+        assert_eq!(result.addr().port(), port);
+        assert!(result.local_port().is_some_and(|port| port != 0));
+    }
+
+    #[test]
+    fn dedup_by_subnet_considers_every_address_of_a_server() {
+        let addrs = |list: &[&str]| {
+            Some(
+                list.iter()
+                    .map(|addr| addr.parse::<SocketAddr>().unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let names = ["a", "pool", "b", "unresolved"].map(String::from);
+        let resolved = vec![
+            (&names[0], addrs(&["192.0.2.1:123"])),
+            // Its IPv4 address is taken, its second one is not
+            (
+                &names[1],
+                addrs(&["[2001:db8::1]:123", "192.0.2.2:123", "198.51.100.1:123"]),
+            ),
+            (&names[2], addrs(&["192.0.2.3:123", "198.51.100.2:123"])),
+            (&names[3], None),
+        ];
+
+        let mut notes = Vec::new();
+        let kept = dedup_resolved(resolved, (24, 48), &mut notes);
+        let kept: Vec<_> = kept
+            .into_iter()
+            .map(|(server, addr)| (server.as_str(), addr.map(|addr| addr.to_string())))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                ("a", Some("192.0.2.1:123".to_string())),
+                ("pool", Some("198.51.100.1:123".to_string())),
+                ("unresolved", None),
+            ]
+        );
+        assert_eq!(
+            notes,
+            ["skipping b (192.0.2.3, 198.51.100.2), in the same /24 as a (192.0.2.1)"]
+        );
+    }
 }
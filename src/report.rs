@@ -0,0 +1,193 @@
+use std::io::ErrorKind;
+
+use chrono::SecondsFormat;
+use ntp_client::{
+    CheckSummary, ExtensionField, FamilyComparison, FamilyResult, NTPResult, QueryFailure,
+    ReferenceReport, ServerReport,
+};
+
+use crate::cli::Cli;
+
+/// Writes what a query found per server to stderr, followed by its notes and warnings. For a
+/// failed query this is whatever it got as far as; without network connectivity at all, the
+/// single error says more than a failure per server would.
+pub fn print_outcome(outcome: Result<&CheckSummary, &std::io::Error>, args: &Cli) {
+    let (servers, notes, warnings) = match outcome {
+        Ok(summary) => (&summary.servers, &summary.notes, &summary.warnings),
+        Err(err) => match QueryFailure::of(err) {
+            Some(failure) => (&failure.servers, &failure.notes, &failure.warnings),
+            None => return,
+        },
+    };
+    if !matches!(outcome, Err(err) if err.kind() == ErrorKind::NetworkUnreachable) {
+        for server in servers {
+            print_server(server, args);
+        }
+    }
+    for note in notes {
+        eprintln!("note: {}", note);
+    }
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+fn print_server(server: &ServerReport, args: &Cli) {
+    eprint!("{} => ", server.host);
+
+    let exchange = server.exchange.as_ref();
+    match (server.offset, &server.error) {
+        (Some(offset), _) => {
+            let note = match exchange.and_then(NTPResult::low_confidence) {
+                Some(reason) => format!(" [{}, low confidence]", reason),
+                None => String::new(),
+            };
+            match args.get_clamp_offset().map(|limit| limit as i64) {
+                Some(limit) if offset.abs() > limit => eprintln!(
+                    "{}ms away from local system time [clamped]{}",
+                    offset.clamp(-limit, limit),
+                    note
+                ),
+                _ => eprintln!("{}ms away from local system time{}", offset, note),
+            }
+        }
+        (None, Some(err)) => eprintln!("? [{}]", err),
+        (None, None) => eprintln!("?"),
+    }
+    // Also for a reply excluded as unsynchronized, whose header shows why
+    if let Some(exchange) = exchange.filter(|_| args.get_verbose()) {
+        print_exchange(exchange);
+    }
+    if let Some(sent) = args
+        .get_extension_field()
+        .filter(|_| server.offset.is_some())
+    {
+        print_extension_fields(sent, &server.extension_fields);
+    }
+}
+
+/// Prints the exchange behind a result for --verbose: the four timestamps, the delay and offset
+/// computed from them, and the header fields of the response.
+fn print_exchange(time: &NTPResult) {
+    let response = time.response();
+    let port = match time.local_port() {
+        Some(port) => format!("from port {}", port),
+        None => "over TLS".to_string(),
+    };
+    eprintln!(
+        "  request:  version {}, sent {} to {}",
+        time.version(),
+        port,
+        time.addr()
+    );
+    eprintln!(
+        "  response: LI {}, version {}, mode {}, stratum {}",
+        response.leap_indicator(),
+        response.version(),
+        response.mode(),
+        response.stratum()
+    );
+    for (name, t) in ["t1", "t2", "t3", "t4"].iter().zip(time.times()) {
+        eprintln!(
+            "  {} {}",
+            name,
+            t.to_rfc3339_opts(SecondsFormat::Nanos, true)
+        );
+    }
+    eprintln!("  delay {}ms, offset {}ms", time.delay(), time.offset());
+}
+
+/// Prints whether a server echoed the extension field that was sent, and which ones it returned.
+fn print_extension_fields(sent: &ExtensionField, received: &[ExtensionField]) {
+    if received.is_empty() {
+        eprintln!(
+            "  extension field 0x{:04x}: ignored (none returned)",
+            sent.field_type
+        );
+        return;
+    }
+    // The value may come back with the padding that was added on the wire
+    let echoed = received
+        .iter()
+        .any(|field| field.field_type == sent.field_type && field.value.starts_with(&sent.value));
+    eprintln!(
+        "  extension field 0x{:04x}: {}",
+        sent.field_type,
+        if echoed { "echoed" } else { "not echoed" }
+    );
+    for field in received {
+        let value: String = field.value.iter().map(|b| format!("{:02x}", b)).collect();
+        eprintln!(
+            "    returned 0x{:04x} ({} bytes) {}",
+            field.field_type,
+            field.value.len(),
+            value
+        );
+    }
+}
+
+/// Prints every server's offset relative to the reference, after the reference's own offset
+/// from the local clock for context.
+pub fn print_reference(report: &ReferenceReport) {
+    println!(
+        "{} => {}ms away from local system time [reference]",
+        report.reference, report.reference_offset
+    );
+    for server in &report.servers {
+        match (server.offset, &server.error) {
+            (Some(offset), _) => println!(
+                "{} => {}ms away from {}",
+                server.host, offset, report.reference
+            ),
+            (None, error) => println!(
+                "{} => ? [{}]",
+                server.host,
+                error.as_deref().unwrap_or("no response")
+            ),
+        }
+    }
+}
+
+/// Prints every server's IPv4 and IPv6 offsets side by side, and whether they are consistent.
+pub fn print_families(comparisons: &[FamilyComparison]) {
+    for comparison in comparisons {
+        println!("{}", comparison.host);
+        if let Some(err) = &comparison.error {
+            println!("  ? [{}]", err);
+            continue;
+        }
+        print_family("IPv4", &comparison.ipv4);
+        print_family("IPv6", &comparison.ipv6);
+
+        if let Some((difference, tolerance)) = comparison.difference() {
+            let verdict = if difference.abs() <= tolerance {
+                "consistent"
+            } else {
+                "INCONSISTENT"
+            };
+            println!(
+                "  IPv6 - IPv4 = {}ms ({}, tolerance {}ms)",
+                difference, verdict, tolerance
+            );
+        }
+    }
+}
+
+fn print_family(family: &str, result: &FamilyResult) {
+    let Some(addr) = result.addr else {
+        println!("  {} ? [no address]", family);
+        return;
+    };
+    match (result.offset, result.delay, &result.error) {
+        (Some(offset), Some(delay), _) => println!(
+            "  {} {} => {}ms away from local system time (delay {}ms)",
+            family, addr, offset, delay
+        ),
+        (_, _, error) => println!(
+            "  {} {} => ? [{}]",
+            family,
+            addr,
+            error.as_deref().unwrap_or("no response")
+        ),
+    }
+}
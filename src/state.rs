@@ -0,0 +1,52 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use chrono::{DateTime, Utc};
+
+/// An offset measurement persisted between runs so that drift can be computed.
+pub struct Measurement {
+    pub at: DateTime<Utc>,
+    // Offset from the local clock in milliseconds
+    pub offset: f64,
+}
+
+impl Measurement {
+    /// Returns the local clock's frequency error in parts per million relative to an earlier
+    /// measurement. A positive value means the servers pulled ahead, i.e. the local clock runs
+    /// slow and its frequency should be raised by that amount.
+    pub fn ppm_since(&self, earlier: &Measurement) -> f64 {
+        let elapsed_ms = (self.at - earlier.at).num_milliseconds() as f64;
+        (self.offset - earlier.offset) / elapsed_ms * 1e6
+    }
+}
+
+/// Reads the last measurement, or `None` if the state file does not exist yet.
+/// The file holds a single line: `<RFC3339 time> <offset ms>`.
+pub fn load(path: &Path) -> Result<Option<Measurement>, std::io::Error> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a valid state file", path.display()),
+        )
+    };
+    let (at, offset) = content.trim().split_once(' ').ok_or_else(invalid)?;
+    let at = DateTime::parse_from_rfc3339(at).map_err(|_| invalid())?;
+    let offset = offset.parse().map_err(|_| invalid())?;
+
+    Ok(Some(Measurement {
+        at: at.with_timezone(&Utc),
+        offset,
+    }))
+}
+
+pub fn save(path: &Path, measurement: &Measurement) -> Result<(), std::io::Error> {
+    fs::write(
+        path,
+        format!("{} {}\n", measurement.at.to_rfc3339(), measurement.offset),
+    )
+}
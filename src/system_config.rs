@@ -0,0 +1,93 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+// Configuration files of ntpd and chrony, in the order they are tried
+const SYSTEM_CONFIGS: [&str; 3] = [
+    "/etc/ntp.conf",
+    "/etc/chrony/chrony.conf",
+    "/etc/chrony.conf",
+];
+
+/// A `server` or `pool` directive, or a `--server` argument.
+#[derive(Debug, Clone)]
+pub struct ConfiguredServer {
+    pub host: String,
+    // NTP version to query this server with, if the directive sets one
+    pub version: Option<u8>,
+}
+
+/// Reads the `server` and `pool` directives of the first ntpd/chrony configuration file found.
+pub fn read_system_servers() -> Result<Vec<ConfiguredServer>, std::io::Error> {
+    let path = SYSTEM_CONFIGS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "no NTP configuration found (looked in {})",
+                    SYSTEM_CONFIGS.join(", ")
+                ),
+            )
+        })?;
+
+    let servers = parse_servers(&fs::read_to_string(path)?).map_err(|err| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })?;
+    if servers.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no server or pool directives in {}", path.display()),
+        ));
+    }
+    Ok(servers)
+}
+
+/// Extracts every `server <host> [options]` and `pool <host> [options]` line.
+/// Comments and unrelated directives are ignored, as are ntpd's 127.127.t.u pseudo-addresses,
+/// which name local reference clock drivers rather than NTP servers.
+fn parse_servers(config: &str) -> Result<Vec<ConfiguredServer>, String> {
+    let mut servers = Vec::new();
+
+    for line in config.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let spec = match line.split_once(char::is_whitespace) {
+            Some(("server" | "pool", spec)) if !spec.trim().starts_with("127.127.") => spec,
+            _ => continue,
+        };
+        servers.push(parse_server(spec)?);
+    }
+    Ok(servers)
+}
+
+/// Parses `<host> [options]` as written after `server` in a configuration file. The only option
+/// used is the NTP version, written `version=N` or, as ntpd does, `version N`; others are ignored.
+pub fn parse_server(spec: &str) -> Result<ConfiguredServer, String> {
+    let mut words = spec.split_whitespace();
+    let host = words.next().ok_or("missing server name")?;
+
+    let mut version = None;
+    while let Some(word) = words.next() {
+        let value = match word.strip_prefix("version") {
+            Some("") => words.next(),
+            Some(value) => value.strip_prefix('='),
+            None => continue,
+        };
+        version = Some(parse_version(value.unwrap_or_default())?);
+    }
+    Ok(ConfiguredServer {
+        host: host.to_string(),
+        version,
+    })
+}
+
+/// Parses an NTP version number, which must be between 1 and 4.
+fn parse_version(value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(version @ 1..=4) => Ok(version),
+        _ => Err(format!("invalid NTP version {:?} (expected 1 to 4)", value)),
+    }
+}